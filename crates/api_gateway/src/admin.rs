@@ -0,0 +1,392 @@
+use crate::auth::AdminUser;
+use crate::handlers::ApiError;
+use crate::queue;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Duration, Utc};
+use common::{InstanceStatus, Role, StopReason, TaskInstance};
+use data_models::{Db, InstanceFilter};
+use config_manager::get_config;
+use deploy_service::capacity::Load;
+use deploy_service::{Deployer, NodeStatus};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+fn audit(db: &Db, admin: &AdminUser, action: &str, target: &str, detail: &str) -> Result<(), ApiError> {
+    db.record_audit(admin.0.id, action, target, detail)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct InstanceQuery {
+    task: Option<String>,
+    user: Option<String>,
+    status: Option<InstanceStatus>,
+    older_than_secs: Option<i64>,
+}
+
+pub async fn list_instances(
+    _admin: AdminUser,
+    query: web::Query<InstanceQuery>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let user_id = match &query.user {
+        Some(name) => match db.find_user(name)? {
+            Some(u) => Some(u.id),
+            None => return Ok(HttpResponse::Ok().json(Vec::<TaskInstance>::new())),
+        },
+        None => None,
+    };
+    let filter = InstanceFilter {
+        task_name: query.task.clone(),
+        user_id,
+        status: query.status,
+        created_before: query.older_than_secs.map(|s| Utc::now() - Duration::seconds(s)),
+    };
+    Ok(HttpResponse::Ok().json(db.list_instances_filtered(&filter)?))
+}
+
+#[derive(Deserialize)]
+pub struct BulkStopReq {
+    instance_ids: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct KillTaskReq {
+    task: String,
+}
+
+#[derive(Serialize)]
+pub struct BulkFailure {
+    id: i32,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct BulkResult {
+    stopped: Vec<i32>,
+    failed: Vec<BulkFailure>,
+}
+
+/// Stop each Running instance in `instances`; anything else is reported as
+/// failed. A failed audit insert is logged and does not end the run.
+async fn stop_all(
+    db: &Db,
+    admin: &AdminUser,
+    deployer: &Mutex<Deployer>,
+    instances: Vec<TaskInstance>,
+    action: &str,
+) -> BulkResult {
+    let mut result = BulkResult::default();
+    let mut d = deployer.lock().await;
+    for inst in instances {
+        if inst.status != InstanceStatus::Running {
+            let error = format!("not running ({:?})", inst.status);
+            result.failed.push(BulkFailure { id: inst.id, error });
+            continue;
+        }
+        match d.stop(&inst, StopReason::Admin).await {
+            Ok(()) => {
+                let target = format!("instance:{}", inst.id);
+                if let Err(e) = audit(db, admin, action, &target, &inst.task_name) {
+                    warn!("Audit of {} on {} failed: {}", action, target, e);
+                }
+                result.stopped.push(inst.id);
+            }
+            Err(e) => result.failed.push(BulkFailure { id: inst.id, error: e.to_string() }),
+        }
+    }
+    result
+}
+
+pub async fn stop_instances(
+    admin: AdminUser,
+    body: web::Json<BulkStopReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let mut found = Vec::new();
+    let mut result = BulkResult::default();
+    for id in &body.instance_ids {
+        match db.find_instance_by_id(*id)? {
+            Some(inst) => found.push(inst),
+            None => result.failed.push(BulkFailure { id: *id, error: "not found".into() }),
+        }
+    }
+    let stopped = stop_all(&db, &admin, &deployer, found, "force_stop").await;
+    result.stopped = stopped.stopped;
+    result.failed.extend(stopped.failed);
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn kill_task(
+    admin: AdminUser,
+    body: web::Json<KillTaskReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let filter = InstanceFilter {
+        task_name: Some(body.task.clone()),
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    };
+    let running = db.list_instances_filtered(&filter)?;
+    audit(&db, &admin, "kill_task", &format!("task:{}", body.task), &format!("{} running", running.len()))?;
+    let result = stop_all(&db, &admin, &deployer, running, "force_stop").await;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Deserialize)]
+pub struct ExtendReq {
+    instance_id: i32,
+    secs: u64,
+}
+
+pub async fn extend_instance(
+    admin: AdminUser,
+    body: web::Json<ExtendReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let inst = db
+        .find_instance_by_id(body.instance_id)?
+        .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
+
+    let mut d = deployer.lock().await;
+    d.extend_by(&inst, body.secs).await?;
+    audit(&db, &admin, "extend", &format!("instance:{}", inst.id), &format!("{}s", body.secs))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct BanReq {
+    username: String,
+    #[serde(default = "default_true")]
+    banned: bool,
+    /// Also stop every running instance the user owns.
+    #[serde(default)]
+    stop_instances: bool,
+}
+fn default_true() -> bool { true }
+
+pub async fn ban_user(
+    admin: AdminUser,
+    body: web::Json<BanReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    if body.username == admin.0.username {
+        return Err(ApiError::BadRequest("cannot ban yourself".into()));
+    }
+    let db = Db::new()?;
+    let user = db
+        .find_user(&body.username)?
+        .ok_or_else(|| ApiError::BadRequest("User not found".into()))?;
+    db.set_user_banned(user.id, body.banned)?;
+    let action = if body.banned { "ban" } else { "unban" };
+    audit(&db, &admin, action, &format!("user:{}", user.username), "")?;
+
+    if body.banned && body.stop_instances {
+        let running = db.list_instances_for_user(user.id)?;
+        let result = stop_all(&db, &admin, &deployer, running, "force_stop").await;
+        return Ok(HttpResponse::Ok().json(result));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn usage(_admin: AdminUser) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    Ok(HttpResponse::Ok().json(db.task_usage()?))
+}
+
+#[derive(Deserialize)]
+pub struct LimitQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+}
+fn default_limit() -> i64 { 100 }
+
+pub async fn audit_log(
+    _admin: AdminUser,
+    query: web::Query<LimitQuery>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    Ok(HttpResponse::Ok().json(db.list_audit(query.limit)?))
+}
+
+/// Submissions of flags that belong to another player's instance.
+pub async fn shared_flags(
+    _admin: AdminUser,
+    query: web::Query<LimitQuery>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    Ok(HttpResponse::Ok().json(db.list_shared_flag_submissions(query.limit)?))
+}
+
+#[derive(Deserialize)]
+pub struct FlagQuery {
+    instance_id: i32,
+}
+
+#[derive(Serialize)]
+pub struct FlagResp {
+    instance_id: i32,
+    flag: Option<String>,
+}
+
+pub async fn instance_flag(
+    admin: AdminUser,
+    query: web::Query<FlagQuery>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let inst = db
+        .find_instance_by_id(query.instance_id)?
+        .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
+    audit(&db, &admin, "read_flag", &format!("instance:{}", inst.id), "")?;
+    Ok(HttpResponse::Ok().json(FlagResp { instance_id: inst.id, flag: inst.flag }))
+}
+
+#[derive(Serialize)]
+pub struct NodeInfo {
+    #[serde(flatten)]
+    status: NodeStatus,
+    load: Load,
+}
+
+/// Configured Docker nodes with their health and running load.
+pub async fn nodes(
+    _admin: AdminUser,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let cfg = get_config();
+    let mut usage = queue::current_usage(&db, &cfg)?;
+    let d = deployer.lock().await;
+    d.refresh_nodes().await;
+    let list: Vec<NodeInfo> = d
+        .node_status()
+        .into_iter()
+        .map(|status| NodeInfo { load: usage.nodes.remove(&status.name).unwrap_or_default(), status })
+        .collect();
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[derive(Deserialize)]
+pub struct LogsQuery {
+    instance_id: i32,
+    #[serde(default = "default_tail")]
+    tail: u32,
+}
+fn default_tail() -> u32 { 200 }
+
+/// Recent container output of an instance, from whichever node runs it.
+pub async fn instance_logs(
+    admin: AdminUser,
+    query: web::Query<LogsQuery>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let inst = db
+        .find_instance_by_id(query.instance_id)?
+        .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
+    audit(&db, &admin, "read_logs", &format!("instance:{}", inst.id), "")?;
+    let logs = deployer.lock().await.logs(&inst, query.tail).await?;
+    Ok(HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(logs))
+}
+
+#[derive(Deserialize)]
+pub struct FlagCheckReq {
+    flag: String,
+}
+
+#[derive(Serialize)]
+pub struct FlagCheckResp {
+    valid: bool,
+    instance_id: Option<i32>,
+    task_name: Option<String>,
+    user_id: Option<i32>,
+    team_id: Option<i32>,
+}
+
+/// Look up which instance a dynamic flag was issued to, for scoreboards.
+pub async fn check_flag(
+    _admin: AdminUser,
+    body: web::Json<FlagCheckReq>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let resp = match db.find_instance_by_flag(body.flag.trim())? {
+        Some(inst) => FlagCheckResp {
+            valid: true,
+            instance_id: Some(inst.id),
+            task_name: Some(inst.task_name),
+            user_id: Some(inst.user_id),
+            team_id: inst.team_id,
+        },
+        None => FlagCheckResp {
+            valid: false,
+            instance_id: None,
+            task_name: None,
+            user_id: None,
+            team_id: None,
+        },
+    };
+    Ok(HttpResponse::Ok().json(resp))
+}
+
+#[derive(Deserialize)]
+pub struct RoleReq {
+    username: String,
+    role: String,
+}
+
+pub async fn set_role(
+    admin: AdminUser,
+    body: web::Json<RoleReq>,
+) -> Result<impl Responder, ApiError> {
+    let role = Role::parse(&body.role)
+        .ok_or_else(|| ApiError::BadRequest(format!("unknown role {}", body.role)))?;
+    if admin.0.username == body.username && role != Role::Admin {
+        return Err(ApiError::BadRequest("cannot demote yourself".into()));
+    }
+    let db = Db::new()?;
+    let user = db.set_user_role(&body.username, role)?;
+    audit(&db, &admin, "set_role", &format!("user:{}", user.username), role.as_str())?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[derive(Deserialize)]
+pub struct TaskOwnerReq {
+    task: String,
+    username: String,
+}
+
+pub async fn set_task_owner(
+    admin: AdminUser,
+    body: web::Json<TaskOwnerReq>,
+) -> Result<impl Responder, ApiError> {
+    let db = Db::new()?;
+    let user = db
+        .find_user(&body.username)?
+        .ok_or_else(|| ApiError::BadRequest("User not found".into()))?;
+    if !db.set_task_owner(&body.task, user.id)? {
+        return Err(ApiError::BadRequest("Task not found".into()));
+    }
+    audit(&db, &admin, "set_task_owner", &format!("task:{}", body.task), &user.username)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/instances", web::get().to(list_instances))
+        .route("/stop", web::post().to(stop_instances))
+        .route("/extend", web::post().to(extend_instance))
+        .route("/kill-task", web::post().to(kill_task))
+        .route("/ban", web::post().to(ban_user))
+        .route("/usage", web::get().to(usage))
+        .route("/audit", web::get().to(audit_log))
+        .route("/flag", web::get().to(instance_flag))
+        .route("/logs", web::get().to(instance_logs))
+        .route("/nodes", web::get().to(nodes))
+        .route("/flag/check", web::post().to(check_flag))
+        .route("/shared-flags", web::get().to(shared_flags))
+        .route("/role", web::post().to(set_role))
+        .route("/task-owner", web::post().to(set_task_owner));
+}
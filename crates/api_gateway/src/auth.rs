@@ -2,37 +2,87 @@ use actix_web::{dev::Payload, Error as ActixError, FromRequest, HttpRequest};
 use futures_util::future::{ready, Ready};
 use crate::handlers::ApiError;
 use data_models::Db;
-use common::User;
+use common::{ApiScope, User};
 
-pub struct AuthUser(pub User);
+/// Prefix that distinguishes personal API keys from session tokens.
+pub const API_KEY_PREFIX: &str = "ctfk_";
+
+/// How the caller authenticated.
+pub enum Access {
+    Session,
+    /// An API key; an empty scope list means unrestricted.
+    ApiKey(Vec<ApiScope>),
+}
+
+pub struct AuthUser(pub User, pub Access);
+
+/// An authenticated user holding the `admin` role.
+pub struct AdminUser(pub User);
+
+impl AuthUser {
+    /// Reject API keys that were not granted `scope`.
+    pub fn require(&self, scope: ApiScope) -> Result<(), ApiError> {
+        match &self.1 {
+            Access::ApiKey(scopes) if !scopes.is_empty() && !scopes.contains(&scope) => {
+                Err(ApiError::Forbidden(format!("API key lacks the {} scope", scope.as_str())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject API keys entirely, for account management endpoints.
+    pub fn require_session(&self) -> Result<(), ApiError> {
+        match self.1 {
+            Access::Session => Ok(()),
+            Access::ApiKey(_) => Err(ApiError::Forbidden("not allowed with an API key".into())),
+        }
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthUser, ActixError> {
+    // 1) Extract Bearer token
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::BadRequest("Missing token".into()))?;
+
+    // 2) Validate via Db
+    let db = Db::new().map_err(ApiError::Db)?;
+    let auth = if token.starts_with(API_KEY_PREFIX) {
+        db.validate_api_key(token)
+            .map_err(ApiError::Db)?
+            .map(|(user, scopes)| AuthUser(user, Access::ApiKey(scopes)))
+    } else {
+        db.validate_session(token)
+            .map_err(ApiError::Db)?
+            .map(|user| AuthUser(user, Access::Session))
+    };
+    auth.ok_or_else(|| ApiError::BadRequest("Invalid or expired token".into()).into())
+}
 
 impl FromRequest for AuthUser {
     type Error = ActixError;
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        // 1) Extract Bearer token
-        let token_opt = req
-            .headers()
-            .get("Authorization")
-            .and_then(|h| h.to_str().ok())
-            .and_then(|s| s.strip_prefix("Bearer ").map(str::to_string));
-
-        let token = match token_opt {
-            Some(t) => t,
-            None => return ready(Err(ApiError::BadRequest("Missing token".into()).into())),
-        };
-
-        // 2) Validate via Db
-        let db = match Db::new() {
-            Ok(db) => db,
-            Err(e) => return ready(Err(ApiError::Db(e).into())),
-        };
-
-        match db.validate_session(&token) {
-            Err(e) => ready(Err(ApiError::Db(e).into())),
-            Ok(None) => ready(Err(ApiError::BadRequest("Invalid or expired token".into()).into())),
-            Ok(Some(user)) => ready(Ok(AuthUser(user))),
-        }
+        ready(authenticate(req))
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = ActixError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|auth| {
+            auth.require_session()?;
+            if auth.0.is_admin() {
+                Ok(AdminUser(auth.0))
+            } else {
+                Err(ApiError::forbidden("Admin role required"))
+            }
+        }))
     }
 }
@@ -0,0 +1,99 @@
+//! `GET /events`: a Server-Sent Events stream of events on the caller's
+//! instances, such as expiry warnings from the scheduler. Streams share the
+//! app's connection pool and poll it off the async workers.
+
+use crate::auth::AuthUser;
+use crate::handlers::ApiError;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use common::{ApiScope, InstanceEvent, User};
+use data_models::Db;
+use futures_util::stream;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tracing::warn;
+
+/// How often the stream checks for new events.
+const POLL: Duration = Duration::from_secs(2);
+/// Events fetched per query.
+const BATCH: i64 = 100;
+/// Quiet polls between keep-alive comments.
+const KEEPALIVE_POLLS: u32 = 10;
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Resume after this event id. Without it (or `Last-Event-ID`), only
+    /// events from now on are sent.
+    since: Option<i64>,
+}
+
+struct State {
+    db: web::Data<Db>,
+    user: User,
+    cursor: i64,
+    pending: VecDeque<InstanceEvent>,
+    quiet: u32,
+}
+
+pub async fn events(
+    auth: AuthUser,
+    req: HttpRequest,
+    query: web::Query<EventsQuery>,
+    db: web::Data<Db>,
+) -> Result<HttpResponse, ApiError> {
+    auth.require(ApiScope::Read)?;
+    // Browsers send Last-Event-ID when reconnecting an EventSource.
+    let resume = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or(query.since);
+    let cursor = match resume {
+        Some(id) => id,
+        None => db.latest_instance_event_id()?,
+    };
+    let state = State { db, user: auth.0, cursor, pending: VecDeque::new(), quiet: 0 };
+
+    let body = stream::unfold(state, |mut st| async move {
+        loop {
+            if let Some(ev) = st.pending.pop_front() {
+                return Some((Ok::<_, actix_web::Error>(frame(&ev)), st));
+            }
+            tokio::time::sleep(POLL).await;
+            let (db, user, cursor) = (st.db.clone(), st.user.clone(), st.cursor);
+            match web::block(move || db.list_instance_events_for(&user, cursor, BATCH)).await {
+                Ok(Ok(rows)) => {
+                    if let Some(last) = rows.last() {
+                        st.cursor = last.id;
+                    }
+                    st.pending.extend(rows);
+                }
+                Ok(Err(e)) => warn!("Event stream for user {} failed to poll: {}", st.user.id, e),
+                Err(e) => warn!("Event stream for user {} failed to poll: {}", st.user.id, e),
+            }
+            if st.pending.is_empty() {
+                st.quiet += 1;
+                if st.quiet >= KEEPALIVE_POLLS {
+                    st.quiet = 0;
+                    return Some((Ok(Bytes::from_static(b": keepalive\n\n")), st));
+                }
+            } else {
+                st.quiet = 0;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
+}
+
+/// One SSE message; `data` is the event as JSON on a single line.
+fn frame(ev: &InstanceEvent) -> Bytes {
+    let data = serde_json::to_string(ev).unwrap_or_default();
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", ev.id, ev.kind, data))
+}
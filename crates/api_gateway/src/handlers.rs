@@ -1,51 +1,74 @@
+use crate::admin;
+use crate::events;
+use crate::keys;
+use crate::queue;
+use crate::submit;
+use crate::teams;
 use crate::auth::AuthUser;
-use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, Responder, ResponseError, web};
 use chrono::{Duration, Utc};
-use common::TaskInstance;
-use config_manager::get_config;
+use common::{ApiScope, InstanceStatus, Role, StopReason, TaskInstance};
+use config_manager::{DEFAULT_TASK, get_config};
 use data_models::Db;
-use deploy_service::Deployer;
+use deploy_service::error::DeployError;
+use deploy_service::capacity::Blocked;
+use deploy_service::{Deployer, extensions_left};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use tokio::sync::Mutex;
 use thiserror::Error;
 use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum ApiError {
+    // Captcha checks are not wired into any handler yet.
+    #[allow(dead_code)]
     #[error("captcha failed")]
     Captcha,
 
     #[error("deploy service error: {0}")]
-    Deploy(#[from] deploy_service::error::DeployError),
+    Deploy(#[from] DeployError),
 
     #[error("db error: {0}")]
     Db(#[from] common::ServiceError),
 
     #[error("bad request: {0}")]
     BadRequest(String),
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
 }
 
 impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
         match self {
             ApiError::Captcha => HttpResponse::Unauthorized().json("Invalid captcha"),
+            ApiError::Deploy(DeployError::Limit(msg)) => HttpResponse::BadRequest().json(msg.clone()),
             ApiError::Deploy(e) => HttpResponse::InternalServerError().json(e.to_string()),
             ApiError::Db(e) => HttpResponse::InternalServerError().json(e.to_string()),
             ApiError::BadRequest(msg) => HttpResponse::BadRequest().json(msg.clone()),
+            ApiError::Forbidden(msg) => HttpResponse::Forbidden().json(msg.clone()),
+            ApiError::TooManyRequests(msg) => HttpResponse::TooManyRequests().json(msg.clone()),
         }
     }
 }
 
 impl ApiError {
-    fn forbidden(msg: &str) -> actix_web::Error {
-        actix_web::error::InternalError::new(msg.to_string(), StatusCode::FORBIDDEN).into()
+    pub(crate) fn forbidden(msg: &str) -> actix_web::Error {
+        ApiError::Forbidden(msg.to_string()).into()
     }
 }
 
 #[derive(Deserialize)]
 pub struct DeployReq {
     task: String,
+    /// Deploy on behalf of the caller's team instead of the caller alone.
+    #[serde(default)]
+    team: bool,
+    /// Sent by the frontend; not verified yet.
+    #[allow(dead_code)]
     captcha_token: String,
 }
 
@@ -58,9 +81,12 @@ pub struct ActionReq {
 pub struct InstanceListItem {
     id: i32,
     task_name: String,
+    team_id: Option<i32>,
     expires_in_secs: u64,
+    extensions_left: u32,
     endpoint: String,
     status: String,
+    stop_reason: Option<StopReason>,
 }
 
 #[derive(Serialize)]
@@ -68,24 +94,60 @@ pub struct DeployResp {
     pub instance: TaskInstance,
 }
 
+#[derive(Serialize)]
+pub struct QueuedResp {
+    pub queued: queue::QueueStatus,
+}
+
 pub async fn deploy(
     auth: AuthUser,
     body: web::Json<DeployReq>,
     deployer: web::Data<Mutex<Deployer>>,
 ) -> Result<impl Responder, ApiError> {
+    auth.require(ApiScope::Deploy)?;
     let cfg = get_config();
+    if !cfg.has_task(&body.task) {
+        return Err(ApiError::BadRequest(format!("unknown task {}", body.task)));
+    }
     let user_id = auth.0.id;
     let db = Db::new()?;
     let running = db.count_running_instances_for_user(user_id)?;
     if running >= cfg.sessions.clone().max_instances.into() {
         return Err(ApiError::BadRequest("instance limit reached".into()));
     }
+    let team_id = if body.team {
+        let tid = auth.0.team_id
+            .ok_or_else(|| ApiError::BadRequest("not in a team".into()))?;
+        let team_running = db.count_running_instances_for_team(tid)?;
+        if team_running >= cfg.sessions.max_team_instances.into() {
+            return Err(ApiError::BadRequest("team instance limit reached".into()));
+        }
+        Some(tid)
+    } else {
+        None
+    };
+
+    let d = deployer.lock().await;
+    // Once anyone is waiting, new deploys queue behind them, so the queue
+    // stays first come first served.
+    let waiting = db.deploy_queue_len()? > 0;
+    d.refresh_nodes().await;
+    let node = match queue::current_usage(&db, &cfg)?.admit(&cfg, &body.task, |n| d.node_healthy(n)) {
+        Err(Blocked::Never(what)) => return Err(queue::never_fits(&body.task, what)),
+        Ok(node) if !waiting => node,
+        _ => {
+            let queued = queue::enqueue(&db, &cfg, user_id, &body.task, team_id)?;
+            return Ok(HttpResponse::Accepted().json(QueuedResp { queued }));
+        }
+    };
+    let dr = d.deploy(&body.task, user_id, team_id, &node).await?;
 
-    let mut d = deployer.lock().unwrap();
-    let dr = d.deploy(&body.task).await?;
-
-    // persist under user:
+    // persist under user, so it counts against capacity while it starts:
     let saved = db.create_instance_for_user(&dr.instance, auth.0.id)?;
+    // Probe without holding up other deploys.
+    let prober = Deployer::clone(&d);
+    drop(d);
+    prober.wait_ready(&saved).await?;
     Ok(HttpResponse::Ok().json(DeployResp { instance: saved }))
 }
 
@@ -94,18 +156,19 @@ pub async fn stop(
     body: web::Json<ActionReq>,
     deployer: web::Data<Mutex<Deployer>>,
 ) -> Result<impl Responder, actix_web::Error> {
+    auth.require(ApiScope::Stop)?;
     let db = Db::new().map_err(ApiError::Db)?;
     let inst = db
         .find_instance_by_id(body.instance_id)
         .map_err(ApiError::Db)?
         .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
 
-    if inst.user_id != auth.0.id {
+    if !auth.0.can_manage(&inst) {
         return Err(ApiError::forbidden("Not your instance"));
     }
 
-    let mut d = deployer.lock().unwrap();
-    d.stop(&inst).await.map_err(ApiError::Deploy)?;
+    let mut d = deployer.lock().await;
+    d.stop(&inst, StopReason::User).await.map_err(ApiError::Deploy)?;
     Ok(HttpResponse::NoContent().finish())
 }
 
@@ -114,63 +177,122 @@ pub async fn restart(
     body: web::Json<ActionReq>,
     deployer: web::Data<Mutex<Deployer>>,
 ) -> Result<impl Responder, actix_web::Error> {
+    auth.require(ApiScope::Stop)?;
     let db = Db::new().map_err(ApiError::Db)?;
     let inst = db
         .find_instance_by_id(body.instance_id)
         .map_err(ApiError::Db)?
         .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
 
-    if inst.user_id != auth.0.id {
+    if !auth.0.can_manage(&inst) {
         return Err(ApiError::forbidden("Not your instance"));
     }
 
-    let mut d = deployer.lock().unwrap();
+    let mut d = deployer.lock().await;
     d.restart(&inst).await.map_err(ApiError::Deploy)?;
     Ok(HttpResponse::NoContent().finish())
 }
 
+/// Recreate the instance's container from a clean image; the address, flag
+/// and expiry stay the same.
+pub async fn reset(
+    auth: AuthUser,
+    body: web::Json<ActionReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, actix_web::Error> {
+    auth.require(ApiScope::Stop)?;
+    let db = Db::new().map_err(ApiError::Db)?;
+    let inst = db
+        .find_instance_by_id(body.instance_id)
+        .map_err(ApiError::Db)?
+        .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
+
+    if !auth.0.can_manage(&inst) {
+        return Err(ApiError::forbidden("Not your instance"));
+    }
+    if inst.status != InstanceStatus::Running {
+        return Err(ApiError::BadRequest("Instance is not running".into()).into());
+    }
+
+    let mut d = deployer.lock().await;
+    let fresh = d.reset(&inst).await.map_err(ApiError::Deploy)?;
+    let prober = Deployer::clone(&d);
+    drop(d);
+    prober.wait_ready(&fresh).await.map_err(ApiError::Deploy)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 pub async fn extend(
     auth: AuthUser,
     body: web::Json<ActionReq>,
     deployer: web::Data<Mutex<Deployer>>,
 ) -> Result<impl Responder, actix_web::Error> {
+    auth.require(ApiScope::Stop)?;
     let db = Db::new().map_err(ApiError::Db)?;
     let inst = db
         .find_instance_by_id(body.instance_id)
         .map_err(ApiError::Db)?
         .ok_or_else(|| ApiError::BadRequest("Instance not found".into()))?;
 
-    if inst.user_id != auth.0.id {
+    if !auth.0.can_manage(&inst) {
         return Err(ApiError::forbidden("Not your instance"));
     }
 
-    let mut d = deployer.lock().unwrap();
-    let ttl = get_config().ports.default_ttl_secs;
-    d.extend(&inst, ttl).await.map_err(ApiError::Deploy)?;
+    let mut d = deployer.lock().await;
+    d.extend(&inst).await.map_err(ApiError::Deploy)?;
     Ok(HttpResponse::NoContent().finish())
 }
 
 pub async fn list_instances(auth: AuthUser) -> Result<impl Responder, actix_web::Error> {
+    auth.require(ApiScope::Read)?;
     let db = Db::new().map_err(ApiError::Db)?;
     let rows = db
-        .list_instances_for_user(auth.0.id)
+        .list_instances_visible_to(&auth.0)
         .map_err(ApiError::Db)?;
     let now = chrono::Utc::now();
+    let cfg = get_config();
     let items: Vec<InstanceListItem> = rows
         .into_iter()
         .map(|i| InstanceListItem {
+            extensions_left: extensions_left(&i, cfg.task(&i.task_name), &cfg.ports),
             id: i.id,
             task_name: i.task_name,
+            team_id: i.team_id,
             expires_in_secs: i.expires_at.signed_duration_since(now).num_seconds().max(0) as u64,
             endpoint: i.endpoint,
             status: format!("{:?}", i.status),
+            stop_reason: i.stop_reason,
         })
         .collect();
     Ok(HttpResponse::Ok().json(items))
 }
 
 #[derive(Deserialize)]
-struct TokenReq {
+pub struct RebuildReq {
+    task: String,
+}
+
+/// Rebuild a task image. Allowed for admins and for the author who owns the task.
+pub async fn rebuild(
+    auth: AuthUser,
+    body: web::Json<RebuildReq>,
+    deployer: web::Data<Mutex<Deployer>>,
+) -> Result<impl Responder, actix_web::Error> {
+    auth.require_session()?;
+    let db = Db::new().map_err(ApiError::Db)?;
+    let owner = db.task_owner(&body.task).map_err(ApiError::Db)?;
+    let is_owner = auth.0.role == Role::Author && owner == Some(auth.0.id);
+    if !auth.0.is_admin() && !is_owner {
+        return Err(ApiError::forbidden("Not your task"));
+    }
+
+    let mut d = deployer.lock().await;
+    d.rebuild(&body.task).await.map_err(ApiError::Deploy)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct TokenReq {
     username: String,
 }
 
@@ -185,18 +307,11 @@ pub async fn token(body: web::Json<TokenReq>) -> Result<impl Responder, ApiError
 
     let user = db.find_or_create_user(&body.username)?;
 
-    if let Some(existing_token) = db.find_valid_session_for_user(user.id)? {
-        if let Some(sess) = db.get_session(&existing_token)? {
-            return Ok(HttpResponse::Ok().json(TokenResp {
-                token: existing_token,
-                expires_at: sess.expires_at.timestamp(),
-            }));
-        }
-    }
-
+    // Only a hash of each token is stored, so an existing session cannot be
+    // handed out again; every call issues a fresh token.
     let cfg = get_config();
     let ttl_hours = cfg.sessions.clone().ttl_hours;
-    let expires = Utc::now() + Duration::hours(ttl_hours.into());
+    let expires = Utc::now() + Duration::hours(ttl_hours);
 
     let new_token = Uuid::new_v4().to_string();
     db.create_session(&new_token, user.id, expires)?;
@@ -212,9 +327,17 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
         .route("/deploy", web::post().to(deploy))
         .route("/stop", web::post().to(stop))
         .route("/restart", web::post().to(restart))
+        .route("/reset", web::post().to(reset))
         .route("/extend", web::post().to(extend))
         .route("/instances", web::get().to(list_instances))
-        .route("/tasks", web::get().to(list_tasks));
+        .route("/tasks", web::get().to(list_tasks))
+        .route("/rebuild", web::post().to(rebuild))
+        .route("/submit", web::post().to(submit::submit))
+        .route("/events", web::get().to(events::events))
+        .service(web::scope("/admin").configure(admin::configure_routes))
+        .service(web::scope("/teams").configure(teams::configure_routes))
+        .service(web::scope("/keys").configure(keys::configure_routes))
+        .service(web::scope("/queue").configure(queue::configure_routes));
 }
 
 #[derive(Serialize)]
@@ -222,6 +345,10 @@ pub struct TaskInfo {
     pub name: String,
     pub protocol: String,
     pub container_port: u16,
+    pub description: String,
+    pub category: Option<String>,
+    pub author: Option<String>,
+    pub ttl_secs: u64,
 }
 
 pub async fn list_tasks() -> Result<impl Responder, ApiError> {
@@ -229,11 +356,15 @@ pub async fn list_tasks() -> Result<impl Responder, ApiError> {
     let tasks: Vec<TaskInfo> = cfg
         .tasks
         .iter()
-        .filter(|(name, _)| name.as_str() != "_default")
+        .filter(|(name, _)| name.as_str() != DEFAULT_TASK)
         .map(|(name, tc)| TaskInfo {
             name: name.clone(),
             protocol: tc.protocol.clone(),
             container_port: tc.container_port,
+            description: tc.description.clone(),
+            category: tc.category.clone(),
+            author: tc.author.clone(),
+            ttl_secs: tc.ttl_secs(&cfg.ports),
         })
         .collect();
     Ok(HttpResponse::Ok().json(tasks))
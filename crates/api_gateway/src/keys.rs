@@ -0,0 +1,64 @@
+use crate::auth::{API_KEY_PREFIX, AuthUser};
+use crate::handlers::ApiError;
+use actix_web::{HttpResponse, Responder, web};
+use common::{ApiKey, ApiScope, compute_expiry};
+use data_models::Db;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct CreateKeyReq {
+    name: String,
+    /// Omit or leave empty for an unrestricted key.
+    #[serde(default)]
+    scopes: Vec<ApiScope>,
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateKeyResp {
+    /// Only ever returned here; the server keeps a hash.
+    key: String,
+    info: ApiKey,
+}
+
+pub async fn create_key(
+    auth: AuthUser,
+    body: web::Json<CreateKeyReq>,
+) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    let db = Db::new()?;
+    let key = format!("{}{}", API_KEY_PREFIX, Uuid::new_v4().simple());
+    let expires = body.expires_in_secs.map(compute_expiry);
+    let info = db.create_api_key(auth.0.id, &body.name, &key, &body.scopes, expires)?;
+    Ok(HttpResponse::Ok().json(CreateKeyResp { key, info }))
+}
+
+pub async fn list_keys(auth: AuthUser) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    let db = Db::new()?;
+    Ok(HttpResponse::Ok().json(db.list_api_keys(auth.0.id)?))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeKeyReq {
+    id: i32,
+}
+
+pub async fn revoke_key(
+    auth: AuthUser,
+    body: web::Json<RevokeKeyReq>,
+) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    let db = Db::new()?;
+    if !db.revoke_api_key(auth.0.id, body.id)? {
+        return Err(ApiError::BadRequest("Key not found".into()));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::post().to(create_key))
+        .route("", web::get().to(list_keys))
+        .route("/revoke", web::post().to(revoke_key));
+}
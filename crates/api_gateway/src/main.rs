@@ -1,31 +1,73 @@
 mod handlers;
 mod auth;
+mod admin;
+mod teams;
+mod keys;
+mod submit;
+mod events;
+mod queue;
 
 use actix_web::{App, HttpServer};
-use common::init_logging;
-use config_manager::get_config;
-use std::sync::Mutex;
+use common::{init_logging, reload::watch_config, Role, ServiceError};
+use config_manager::{Config, DEFAULT_TASK, config_arg};
+use tokio::sync::Mutex;
+use tracing::error;
 use actix_cors::Cors;
 use data_models::Db;
 use deploy_service::Deployer;
 use handlers::configure_routes;
 
+/// Record `name` in the tasks table if it has a Dockerfile.
+fn sync_task(db: &Db, cfg: &Config, name: &str) -> Result<(), ServiceError> {
+    let dockerfile = cfg.tasks_dir.join(name).join("Dockerfile");
+    if name == DEFAULT_TASK || !dockerfile.is_file() {
+        return Ok(());
+    }
+    db.ensure_task(name, &dockerfile.to_string_lossy(), cfg.task(name))
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     init_logging();
+    let cfg = match config_manager::init(config_arg().as_deref()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("config error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let cfg = get_config();
     let bind_addr = ("0.0.0.0", 8080);
 
     let deployer = Deployer::new().await.expect("failed to init deployer");
     let deployer_data = actix_web::web::Data::new(Mutex::new(deployer));
     let db = Db::new().expect("DB init failed");
+    let db_data = actix_web::web::Data::new(db.clone());
 
-    for entry in std::fs::read_dir("./tasks")? {
-        let name = entry?.file_name().into_string().unwrap();
-        let path = format!("./tasks/{}/Dockerfile", name);
-        db.ensure_task(&name, &path).expect("failed to seed task");
+    for name in cfg.tasks.keys() {
+        sync_task(&db, &cfg, name).expect("failed to seed task");
     }
+    for name in &cfg.sessions.admins {
+        db.set_user_role(name, Role::Admin).expect("failed to seed admin");
+    }
+    tokio::spawn(queue::run(deployer_data.clone()));
+    let reload_deployer = deployer_data.clone();
+    tokio::spawn(watch_config(move |cfg, report| {
+        let deployer = reload_deployer.clone();
+        async move {
+            for name in report.added.iter().chain(&report.changed) {
+                if let Err(e) = Db::new().and_then(|db| sync_task(&db, &cfg, name)) {
+                    error!("syncing task {} after reload failed: {}", name, e);
+                }
+            }
+            for name in &report.rebuild {
+                if let Err(e) = deployer.lock().await.rebuild(name).await {
+                    error!("rebuilding task {} after reload failed: {}", name, e);
+                }
+            }
+        }
+    }));
+
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
@@ -36,6 +78,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .app_data(deployer_data.clone())
+            .app_data(db_data.clone())
             .configure(configure_routes)
     })
         .bind(bind_addr)?
@@ -0,0 +1,191 @@
+//! The deploy queue. When capacity is full, `/deploy` queues the request
+//! instead of failing; a background worker starts queued deploys in order as
+//! instances stop, and announces each one on the event stream. A queued
+//! deploy that cannot start stays visible on `GET /queue` with the reason
+//! until its owner queues again or leaves.
+
+use crate::auth::AuthUser;
+use crate::handlers::ApiError;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{DateTime, Duration, Utc};
+use common::{ApiScope, InstanceStatus, QueueEntry, ServiceError, TaskInstance};
+use config_manager::{Config, get_config};
+use data_models::{Db, InstanceFilter};
+use deploy_service::Deployer;
+use deploy_service::capacity::{Blocked, Usage};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// How often the worker looks for capacity.
+const POLL: std::time::Duration = std::time::Duration::from_secs(2);
+
+pub const QUEUED_DEPLOY: &str = "queued_deploy";
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    id: i64,
+    task_name: String,
+    /// 1 when next in line, 0 once failed.
+    position: i64,
+    queued_at: DateTime<Utc>,
+    failure: Option<String>,
+    failed_at: Option<DateTime<Utc>>,
+}
+
+impl QueueStatus {
+    pub fn new(entry: QueueEntry, position: i64) -> Self {
+        QueueStatus {
+            id: entry.id,
+            task_name: entry.task_name,
+            position,
+            queued_at: entry.created_at,
+            failure: entry.failure,
+            failed_at: entry.failed_at,
+        }
+    }
+}
+
+/// Load of the running instances, for admission decisions.
+pub fn current_usage(db: &Db, cfg: &Config) -> Result<Usage, ServiceError> {
+    let running = db.list_instances_filtered(&InstanceFilter {
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    })?;
+    Ok(Usage::of(cfg, &running))
+}
+
+/// Message for deploys that cannot ever fit.
+pub fn never_fits(task: &str, what: &str) -> ApiError {
+    ApiError::BadRequest(never_fits_reason(task, what))
+}
+
+fn never_fits_reason(task: &str, what: &str) -> String {
+    format!("task {} needs more {} than the capacity limits allow", task, what)
+}
+
+/// Queue a deploy for the caller, or report the place they already hold.
+pub fn enqueue(db: &Db, cfg: &Config, user_id: i32, task: &str, team_id: Option<i32>) -> Result<QueueStatus, ApiError> {
+    let waiting = db.queue_position(user_id)?.is_some_and(|(e, _)| e.failure.is_none());
+    if !waiting && db.deploy_queue_len()? >= cfg.queue.max_length.into() {
+        return Err(ApiError::TooManyRequests("deploy queue is full".into()));
+    }
+    let entry = db.enqueue_deploy(user_id, task, team_id)?;
+    let (entry, position) = db.queue_position(user_id)?.unwrap_or((entry, 1));
+    Ok(QueueStatus::new(entry, position))
+}
+
+pub async fn my_place(auth: AuthUser) -> Result<impl Responder, ApiError> {
+    auth.require(ApiScope::Read)?;
+    let db = Db::new()?;
+    let status = db.queue_position(auth.0.id)?.map(|(e, pos)| QueueStatus::new(e, pos));
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn leave(auth: AuthUser) -> Result<impl Responder, ApiError> {
+    auth.require(ApiScope::Deploy)?;
+    let db = Db::new()?;
+    if !db.leave_deploy_queue(auth.0.id)? {
+        return Err(ApiError::BadRequest("not queued".into()));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::get().to(my_place))
+        .route("/leave", web::post().to(leave));
+}
+
+/// Start queued deploys as capacity allows, forever.
+pub async fn run(deployer: web::Data<Mutex<Deployer>>) {
+    let db = match Db::new() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Deploy queue worker cannot reach the database: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::time::sleep(POLL).await;
+        if let Err(e) = admit_queued(&db, &deployer).await {
+            error!("Deploy queue pass failed: {}", e);
+        }
+    }
+}
+
+/// One pass over the queue, oldest first. A deploy blocked by global
+/// capacity holds up everything behind it; one blocked only by its task's
+/// limit, or by its owner's instance limit, lets others pass.
+async fn admit_queued(db: &Db, deployer: &Mutex<Deployer>) -> Result<(), ServiceError> {
+    let cfg = get_config();
+    let reason = format!("no capacity within {}s", cfg.queue.timeout_secs);
+    let dropped = db.expire_deploy_queue(Utc::now() - Duration::seconds(cfg.queue.timeout_secs as i64), &reason)?;
+    if dropped > 0 {
+        info!("Dropped {} deploy(s) queued longer than {}s", dropped, cfg.queue.timeout_secs);
+    }
+    let entries = db.list_deploy_queue()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let d = deployer.lock().await;
+    d.refresh_nodes().await;
+    let mut usage = current_usage(db, &cfg)?;
+    for entry in entries {
+        if !cfg.has_task(&entry.task_name) {
+            db.fail_queued_deploy(&entry, &format!("unknown task {}", entry.task_name))?;
+            continue;
+        }
+        if db.count_running_instances_for_user(entry.user_id)? >= cfg.sessions.max_instances.into() {
+            continue;
+        }
+        if let Some(tid) = entry.team_id
+            && db.count_running_instances_for_team(tid)? >= cfg.sessions.max_team_instances.into()
+        {
+            continue;
+        }
+        let node = match usage.admit(&cfg, &entry.task_name, |n| d.node_healthy(n)) {
+            Ok(node) => node,
+            Err(Blocked::Task(_)) => continue,
+            Err(Blocked::Global(_)) => break,
+            Err(Blocked::Never(what)) => {
+                warn!("Dropping queued {} deploy: one instance exceeds the {} limit", entry.task_name, what);
+                db.fail_queued_deploy(&entry, &never_fits_reason(&entry.task_name, what))?;
+                continue;
+            }
+        };
+
+        // Its owner may have left meanwhile.
+        if !db.remove_queue_entry(entry.id)? {
+            continue;
+        }
+        let saved = match d.deploy(&entry.task_name, entry.user_id, entry.team_id, &node).await {
+            Ok(dr) => db.create_instance_for_user(&dr.instance, entry.user_id)?,
+            Err(e) => {
+                error!("Queued {} deploy for user {} failed: {}", entry.task_name, entry.user_id, e);
+                db.fail_queued_deploy(&entry, &e.to_string())?;
+                continue;
+            }
+        };
+        usage.reserve(&cfg, &entry.task_name, &node);
+        info!("Started queued {} deploy for user {} as instance {}", entry.task_name, entry.user_id, saved.id);
+        tokio::spawn(announce(Deployer::clone(&d), db.clone(), entry, saved));
+    }
+    Ok(())
+}
+
+/// Wait for a started queued deploy to become ready, off the deployer lock,
+/// and tell its owner on the event stream, or on `GET /queue` if it failed.
+async fn announce(d: Deployer, db: Db, entry: QueueEntry, inst: TaskInstance) {
+    if let Err(e) = d.wait_ready(&inst).await {
+        error!("Queued {} deploy for user {} failed: {}", entry.task_name, entry.user_id, e);
+        if let Err(e) = db.fail_queued_deploy(&entry, &e.to_string()) {
+            error!("Failed to record failed deploy for user {}: {}", entry.user_id, e);
+        }
+        return;
+    }
+    let message = format!("Your {} instance (#{}) is ready at {}.", inst.task_name, inst.id, inst.endpoint);
+    if let Err(e) = db.record_instance_event(&inst, QUEUED_DEPLOY, &message, None) {
+        error!("Failed to announce instance {}: {}", inst.id, e);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::auth::AuthUser;
+use crate::handlers::ApiError;
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Duration, Utc};
+use common::ApiScope;
+use config_manager::get_config;
+use data_models::{Db, NewSubmission};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SubmitReq {
+    task: String,
+    flag: String,
+}
+
+#[derive(Serialize)]
+pub struct SubmitResp {
+    correct: bool,
+    /// False when the task had already been solved by this user.
+    first_solve: bool,
+}
+
+/// Check a flag against the caller's own (or team's) instance of the task,
+/// falling back to the task's static flag.
+pub async fn submit(
+    auth: AuthUser,
+    body: web::Json<SubmitReq>,
+) -> Result<impl Responder, ApiError> {
+    auth.require(ApiScope::Submit)?;
+    let cfg = get_config();
+    let user = &auth.0;
+    let db = Db::new()?;
+    if !db.task_exists(&body.task)? {
+        return Err(ApiError::BadRequest("Unknown task".into()));
+    }
+
+    let since = Utc::now() - Duration::seconds(cfg.submissions.window_secs as i64);
+    let wrong = db.count_recent_wrong_submissions(user.id, &body.task, since)?;
+    if wrong >= cfg.submissions.max_wrong.into() {
+        return Err(ApiError::TooManyRequests("too many wrong flags, slow down".into()));
+    }
+
+    let flag = body.flag.trim();
+    let mut correct = false;
+    let mut shared_from_instance = None;
+
+    if let Some(inst) = db.find_instance_by_flag(flag)?
+        && inst.task_name == body.task
+    {
+        let own = inst.user_id == user.id
+            || (inst.team_id.is_some() && inst.team_id == user.team_id);
+        if own {
+            correct = true;
+        } else {
+            // Someone else's dynamic flag: reject and keep it as evidence.
+            shared_from_instance = Some(inst.id);
+        }
+    }
+    if !correct && shared_from_instance.is_none() {
+        let static_flag = cfg
+            .tasks
+            .get(&body.task)
+            .and_then(|tc| tc.flag.value.as_deref());
+        correct = static_flag == Some(flag);
+    }
+
+    db.record_submission(&NewSubmission {
+        user_id: user.id,
+        team_id: user.team_id,
+        task_name: &body.task,
+        submitted: flag,
+        correct,
+        shared_from_instance,
+    })?;
+
+    let first_solve = correct && db.record_solve(user.id, user.team_id, &body.task)?;
+    Ok(HttpResponse::Ok().json(SubmitResp { correct, first_solve }))
+}
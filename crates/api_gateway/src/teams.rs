@@ -0,0 +1,90 @@
+use crate::auth::AuthUser;
+use crate::handlers::ApiError;
+use actix_web::{HttpResponse, Responder, web};
+use common::{ApiScope, Team, User};
+use data_models::Db;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct TeamInfo {
+    team: Team,
+    /// Shared with members so they can invite others.
+    join_code: String,
+    members: Vec<String>,
+}
+
+fn team_info(db: &Db, tid: i32) -> Result<TeamInfo, ApiError> {
+    let team = db
+        .find_team_by_id(tid)?
+        .ok_or_else(|| ApiError::BadRequest("Team not found".into()))?;
+    let join_code = db.team_join_code(tid)?.unwrap_or_default();
+    let members = db
+        .team_members(tid)?
+        .into_iter()
+        .map(|u: User| u.username)
+        .collect();
+    Ok(TeamInfo { team, join_code, members })
+}
+
+#[derive(Deserialize)]
+pub struct CreateTeamReq {
+    name: String,
+}
+
+pub async fn create_team(
+    auth: AuthUser,
+    body: web::Json<CreateTeamReq>,
+) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    if auth.0.team_id.is_some() {
+        return Err(ApiError::BadRequest("already in a team".into()));
+    }
+    let db = Db::new()?;
+    let code = Uuid::new_v4().simple().to_string();
+    let team = db.create_team(&body.name, &code, auth.0.id)?;
+    Ok(HttpResponse::Ok().json(team_info(&db, team.id)?))
+}
+
+#[derive(Deserialize)]
+pub struct JoinTeamReq {
+    name: String,
+    join_code: String,
+}
+
+pub async fn join_team(
+    auth: AuthUser,
+    body: web::Json<JoinTeamReq>,
+) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    if auth.0.team_id.is_some() {
+        return Err(ApiError::BadRequest("already in a team".into()));
+    }
+    let db = Db::new()?;
+    let team = db
+        .join_team(auth.0.id, &body.name, &body.join_code)?
+        .ok_or_else(|| ApiError::BadRequest("Invalid team or join code".into()))?;
+    Ok(HttpResponse::Ok().json(team_info(&db, team.id)?))
+}
+
+pub async fn leave_team(auth: AuthUser) -> Result<impl Responder, ApiError> {
+    auth.require_session()?;
+    let db = Db::new()?;
+    db.leave_team(auth.0.id)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn my_team(auth: AuthUser) -> Result<impl Responder, ApiError> {
+    auth.require(ApiScope::Read)?;
+    let tid = auth.0.team_id
+        .ok_or_else(|| ApiError::BadRequest("not in a team".into()))?;
+    let db = Db::new()?;
+    Ok(HttpResponse::Ok().json(team_info(&db, tid)?))
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::post().to(create_team))
+        .route("/join", web::post().to(join_team))
+        .route("/leave", web::post().to(leave_team))
+        .route("/me", web::get().to(my_team));
+}
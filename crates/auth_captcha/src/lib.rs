@@ -15,10 +15,6 @@ pub enum CaptchaError {
 #[derive(Deserialize)]
 struct RecaptchaResponse {
     success: bool,
-    #[serde(default)]
-    challenge_ts: String,
-    #[serde(default)]
-    hostname: String,
 }
 
 pub struct CaptchaVerifier {
@@ -27,13 +23,19 @@ pub struct CaptchaVerifier {
     secret: String,
 }
 
+impl Default for CaptchaVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CaptchaVerifier {
     pub fn new() -> Self {
         let cfg = get_config().captcha.clone();
         Self {
             client: Client::new(),
             url: cfg.verify_url,
-            secret: cfg.secret_key,
+            secret: cfg.secret_key.expose().to_string(),
         }
     }
 
@@ -1,5 +1,9 @@
+pub mod reload;
+
 use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use thiserror::Error;
 use tracing::{subscriber::set_global_default, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -32,6 +36,20 @@ pub struct TaskInstance {
     pub status: InstanceStatus,
     pub user_id: i32,
     pub endpoint: String,
+    /// Set when the instance is owned by the deploying user's team.
+    pub team_id: Option<i32>,
+    /// Per-instance flag. Never serialized so it cannot leak through the
+    /// player API; admins read it through dedicated endpoints.
+    #[serde(skip)]
+    pub flag: Option<String>,
+    /// Times the instance has been extended by its owner.
+    pub extensions: i32,
+    /// Why a stopped instance was stopped; `None` while running.
+    pub stop_reason: Option<StopReason>,
+    /// Docker node the container runs on.
+    pub node: String,
+    /// Endpoints of further exposed services of a multi-container task.
+    pub endpoints: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -39,11 +57,28 @@ pub enum InstanceStatus {
     Running,
     Stopped,
     Expired,
+    /// The container disappeared while the instance was running, or its
+    /// replacement failed to start on a reset.
+    Failed,
+}
+
+/// Who or what stopped an instance.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StopReason {
+    /// Stopped by its owner.
+    User,
+    /// Stopped by an admin, including bans and task kills.
+    Admin,
+    /// Reached `expires_at`.
+    Expired,
+    /// No network or CPU activity for the task's idle timeout.
+    Idle,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserSession {
-    pub session_id: String,
+    pub token_hash: String,
     pub user_id: i32,
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
@@ -54,6 +89,102 @@ pub struct User {
     pub id: i32,
     pub username: String,
     pub created_at: DateTime<Utc>,
+    pub role: Role,
+    pub team_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Instance counts for one task, as shown on the admin usage view.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskUsage {
+    pub task_name: String,
+    pub total_instances: i64,
+    pub running_instances: i64,
+    pub distinct_users: i64,
+}
+
+/// A flag submission whose flag belonged to another player's instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedFlagSubmission {
+    pub submission_id: i32,
+    pub user_id: i32,
+    pub task_name: String,
+    pub instance_id: i32,
+    pub instance_owner_id: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor_id: i32,
+    pub action: String,
+    pub target: String,
+    pub detail: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Something that happened to an instance, as delivered on the player
+/// event stream and to webhooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceEvent {
+    pub id: i64,
+    pub instance_id: i32,
+    pub task_name: String,
+    pub user_id: i32,
+    pub team_id: Option<i32>,
+    /// E.g. `expiry_warning`.
+    pub kind: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A deploy waiting in the queue for capacity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub id: i64,
+    pub user_id: i32,
+    pub task_name: String,
+    pub team_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    /// Why the deploy will not start, once it has failed.
+    pub failure: Option<String>,
+    pub failed_at: Option<DateTime<Utc>>,
+}
+
+/// What an API key may be used for. Session tokens carry every scope.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    Deploy,
+    Stop,
+    Read,
+    Submit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    /// Empty means the key is not restricted.
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Author,
+    Admin,
 }
 
 pub fn init_logging() {
@@ -77,13 +208,106 @@ pub fn ttl_secs_until(expiry: DateTime<Utc>) -> u64 {
     }
 }
 
+/// Keyed hash of a bearer token, as stored in `sessions.token_hash`.
+/// Only this digest ever reaches the database.
+pub fn hash_token(secret: &str, token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
 impl InstanceStatus {
     pub fn as_str(self) -> &'static str {
         match self {
             InstanceStatus::Running => "Running",
             InstanceStatus::Stopped => "Stopped",
             InstanceStatus::Expired => "Expired",
+            InstanceStatus::Failed => "Failed",
+        }
+    }
+}
+
+impl StopReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StopReason::User => "user",
+            StopReason::Admin => "admin",
+            StopReason::Expired => "expired",
+            StopReason::Idle => "idle",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<StopReason> {
+        match s {
+            "user" => Some(StopReason::User),
+            "admin" => Some(StopReason::Admin),
+            "expired" => Some(StopReason::Expired),
+            "idle" => Some(StopReason::Idle),
+            _ => None,
         }
     }
 }
 
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Author => "author",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "player" => Some(Role::Player),
+            "author" => Some(Role::Author),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl ApiScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiScope::Deploy => "deploy",
+            ApiScope::Stop => "stop",
+            ApiScope::Read => "read",
+            ApiScope::Submit => "submit",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ApiScope> {
+        match s {
+            "deploy" => Some(ApiScope::Deploy),
+            "stop" => Some(ApiScope::Stop),
+            "read" => Some(ApiScope::Read),
+            "submit" => Some(ApiScope::Submit),
+            _ => None,
+        }
+    }
+
+    /// Parse the comma separated form stored in `api_keys.scopes`.
+    pub fn parse_list(s: &str) -> Vec<ApiScope> {
+        s.split(',').filter_map(|p| ApiScope::parse(p.trim())).collect()
+    }
+
+    pub fn join(scopes: &[ApiScope]) -> String {
+        scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",")
+    }
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Whether this user may stop, extend or restart `inst`.
+    pub fn can_manage(&self, inst: &TaskInstance) -> bool {
+        self.is_admin()
+            || inst.user_id == self.id
+            || (inst.team_id.is_some() && inst.team_id == self.team_id)
+    }
+}
@@ -0,0 +1,50 @@
+//! Hot reload of the shared configuration on SIGHUP or file changes.
+
+use config_manager::{Config, ReloadReport, get_config, reload, sources_modified};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info};
+
+/// Reload the config whenever the process gets SIGHUP or, with
+/// `reload.watch` set, its sources change on disk. An invalid config is
+/// logged and ignored; after a successful reload `on_reload` runs with the
+/// new config and the task diff. Runs until the process exits.
+pub async fn watch_config<F, Fut>(mut on_reload: F)
+where
+    F: FnMut(Arc<Config>, ReloadReport) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("cannot listen for SIGHUP, config reload disabled: {}", e);
+            return;
+        }
+    };
+    let mut seen = sources_modified(&get_config());
+
+    loop {
+        let cfg = get_config();
+        let poll = Duration::from_secs(cfg.reload.interval_secs.max(1));
+        let trigger = tokio::select! {
+            _ = hangup.recv() => "SIGHUP",
+            _ = tokio::time::sleep(poll), if cfg.reload.watch => {
+                if sources_modified(&cfg) == seen {
+                    continue;
+                }
+                "file change"
+            }
+        };
+        seen = sources_modified(&cfg);
+
+        match reload() {
+            Ok(report) => {
+                info!("config reloaded on {}: {}", trigger, report);
+                on_reload(get_config(), report).await;
+            }
+            Err(e) => error!("config reload on {} rejected, keeping current config: {}", trigger, e),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! `ctf-config validate [--config PATH]` checks the layered config and every
+//! task directory; `ctf-config schema` prints a JSON Schema for editors.
+
+use config_manager::{check, config_arg, json_schema, load};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: ctf-config validate [--config PATH] | ctf-config schema";
+
+fn validate() -> ExitCode {
+    let cfg = match load(config_arg().as_deref()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let report = check(&cfg);
+    for w in &report.warnings {
+        eprintln!("warning: {}", w);
+    }
+    for e in &report.errors {
+        eprintln!("error: {}", e);
+    }
+    if !report.is_ok() {
+        return ExitCode::FAILURE;
+    }
+    let tasks = cfg.tasks.len() - 1;
+    println!("{}: ok, {} task(s)", cfg.path.display(), tasks);
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    match std::env::args().nth(1).as_deref() {
+        Some("validate") => validate(),
+        Some("schema") => {
+            println!("{:#}", json_schema());
+            ExitCode::SUCCESS
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Whole-config checks beyond what loading enforces, for `ctf-config validate`.
+
+use crate::{Config, DEFAULT_TASK, FlagMode, MANIFEST_FILE};
+use schemars::schema_for;
+
+/// Problems found by [`check`]. Errors break deploys; warnings are likely
+/// mistakes that still work.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Check a loaded config against the `tasks/` directory and for values that
+/// parse but cannot work.
+pub fn check(cfg: &Config) -> CheckReport {
+    let mut report = CheckReport::default();
+    let errors = &mut report.errors;
+
+    if cfg.ports.min == 0 || cfg.ports.min > cfg.ports.max {
+        errors.push(format!(
+            "ports: need 0 < min <= max, got min = {} and max = {}",
+            cfg.ports.min, cfg.ports.max
+        ));
+    }
+    for (key, value) in [
+        ("ports.default_ttl_secs", cfg.ports.default_ttl_secs),
+        ("scheduler.poll_interval_secs", cfg.scheduler.poll_interval_secs),
+        ("scheduler.reconcile_interval_secs", cfg.scheduler.reconcile_interval_secs),
+        ("reload.interval_secs", cfg.reload.interval_secs),
+    ] {
+        if value == 0 {
+            errors.push(format!("{} must be positive", key));
+        }
+    }
+    if cfg.scheduler.lease_ttl_secs < 3 {
+        errors.push("scheduler.lease_ttl_secs must be at least 3".into());
+    }
+    if cfg.capacity.max_memory < 0 || cfg.capacity.max_cpus < 0.0 {
+        errors.push("capacity: max_memory and max_cpus must not be negative".into());
+    }
+    if cfg.queue.timeout_secs == 0 {
+        errors.push("queue.timeout_secs must be positive".into());
+    }
+    if cfg.sessions.ttl_hours <= 0 {
+        errors.push("sessions.ttl_hours must be positive".into());
+    }
+    for key in crate::secret::placeholder_secrets(cfg) {
+        errors.push(format!("{} is a placeholder; set it inline, via {}_file or {}_env", key, key, key));
+    }
+    if cfg.notifications.expiry_warning_secs.contains(&0) {
+        errors.push("notifications.expiry_warning_secs must be positive".into());
+    }
+    for (i, hook) in cfg.notifications.webhooks.iter().enumerate() {
+        let url = hook.url.expose();
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            errors.push(format!("notifications.webhooks[{}].url must be an http(s) URL", i));
+        }
+        if hook.timeout_secs == 0 {
+            errors.push(format!("notifications.webhooks[{}].timeout_secs must be positive", i));
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    for node in &cfg.nodes {
+        if node.name.is_empty() || !seen.insert(node.name.as_str()) {
+            errors.push(format!("nodes: names must be set and unique, got {:?}", node.name));
+        }
+        let scheme = node.url.split_once("://").map(|(s, _)| s);
+        if !node.url.is_empty() && !matches!(scheme, Some("unix" | "tcp" | "http" | "https" | "ssh")) {
+            errors.push(format!("node {}: unsupported url {:?}", node.name, node.url));
+        }
+        if node.tls.is_some() && !matches!(scheme, Some("tcp" | "https")) {
+            errors.push(format!("node {}: tls needs a tcp:// or https:// url", node.name));
+        }
+    }
+    if cfg.nodes().iter().all(|n| n.drain) {
+        report.warnings.push("every node is draining; no deploys can start".into());
+    }
+    if cfg.routing.traefik_domain.is_empty() {
+        errors.push("routing.traefik_domain must be set".into());
+    }
+    if cfg.nodes().iter().any(|n| cfg.router_container(&n.name).is_empty()) {
+        errors.push("routing.router_container must be set".into());
+    }
+    match cfg.routing.subnets() {
+        Ok(pool) => {
+            for node in cfg.nodes() {
+                let limit = match node.capacity.max_instances {
+                    0 => cfg.capacity.max_instances,
+                    n => n,
+                };
+                if u64::from(limit) > pool.size() {
+                    report.warnings.push(format!(
+                        "node {}: capacity allows {} instances, but routing.subnet_pool has room for {} networks",
+                        node.name, limit, pool.size()
+                    ));
+                }
+            }
+        }
+        Err(e) => errors.push(format!("routing.subnet_pool: {}", e)),
+    }
+
+    if !cfg.tasks_dir.is_dir() {
+        errors.push(format!("tasks directory {} not found", cfg.tasks_dir.display()));
+    }
+    let mut names: Vec<&String> = cfg.tasks.keys().filter(|n| *n != DEFAULT_TASK).collect();
+    names.sort();
+    if names.is_empty() {
+        report.warnings.push("no tasks defined".into());
+    }
+    for name in names {
+        let task = &cfg.tasks[name];
+        let dir = cfg.tasks_dir.join(name);
+        if !dir.join("Dockerfile").is_file() {
+            report.errors.push(format!("task {}: {}/Dockerfile not found", name, dir.display()));
+        } else if !dir.join(MANIFEST_FILE).is_file() && task.description.is_empty() {
+            report.warnings.push(format!(
+                "task {}: no description; add one in [tasks.{}] or {}/{}",
+                name, name, dir.display(), MANIFEST_FILE
+            ));
+        }
+        for (service, s) in &task.services {
+            if let Some(build) = &s.build
+                && !dir.join(build).join("Dockerfile").is_file()
+            {
+                report.errors.push(format!(
+                    "task {}: service {}: {}/Dockerfile not found",
+                    name, service, dir.join(build).display()
+                ));
+            }
+        }
+        let exposed = task.services.values().filter(|s| s.expose).map(|s| &s.protocol);
+        for protocol in std::iter::once(&task.protocol).chain(exposed) {
+            let entry = if protocol == "tcp" { &cfg.routing.tcp_entry } else { &cfg.routing.http_entry };
+            if entry.is_empty() {
+                report.errors.push(format!("task {}: protocol {} needs routing.{}_entry", name, protocol, protocol));
+            }
+        }
+        if task.ttl_secs(&cfg.ports) > task.max_lifetime_secs(&cfg.ports) {
+            report.errors.push(format!("task {}: ttl_secs exceeds max_lifetime_secs", name));
+        }
+        let conts = std::iter::once(&task.containers).chain(task.services.values().map(|s| &s.containers));
+        let (memory, cpus) = conts
+            .map(|o| cfg.containers.with_overrides(o))
+            .fold((0, 0.0), |(m, c), cont| (m + cont.memory_limit, c + cont.cpu_quota));
+        for (scope, cap) in [("capacity", &cfg.capacity), ("its capacity", &task.capacity)] {
+            if (cap.max_memory > 0 && memory > cap.max_memory) || (cap.max_cpus > 0.0 && cpus > cap.max_cpus)
+            {
+                report.errors.push(format!("task {}: one instance exceeds {}, so it can never deploy", name, scope));
+            }
+        }
+        if !cfg.nodes().iter().any(|n| task.node_labels.iter().all(|(k, v)| n.labels.get(k) == Some(v))) {
+            report.errors.push(format!("task {}: no node carries all of its node_labels", name));
+        }
+        let idle = task.idle_timeout_secs(&cfg.idle);
+        if idle > 0 && idle < 2 * cfg.scheduler.poll_interval_secs {
+            report.warnings.push(format!(
+                "task {}: idle timeout {}s is under two scheduler polls; instances may stop while in use",
+                name, idle
+            ));
+        }
+        if task.flag.mode == FlagMode::Hmac && cfg.flags.secret.is_empty() {
+            report.errors.push(format!("task {}: flag mode hmac needs flags.secret", name));
+        }
+    }
+    report
+}
+
+/// JSON Schema for `Config.toml`, `conf.d` fragments and `task.toml`-style
+/// task tables. No key is marked required, since any layer may supply it.
+pub fn json_schema() -> serde_json::Value {
+    let mut schema = schema_for!(Config).to_value();
+    strip_required(&mut schema);
+    schema
+}
+
+fn strip_required(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.remove("required");
+            map.values_mut().for_each(strip_required);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_required),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reports_missing_dockerfile_and_bad_ports() {
+        let dir = std::env::temp_dir().join(format!("ctf-check-{}", std::process::id()));
+        fs::create_dir_all(dir.join("tasks/web")).unwrap();
+        fs::write(dir.join("tasks/web/Dockerfile"), "FROM scratch\n").unwrap();
+        let path = dir.join(crate::CONFIG_FILE);
+        fs::write(
+            &path,
+            r#"
+[routing]
+traefik_domain = "ctf.test"
+[database]
+url = "postgres://test"
+[captcha]
+provider = "none"
+site_key = ""
+secret_key = ""
+verify_url = ""
+[sessions]
+token_secret = "test"
+[ports]
+min = 4000
+max = 3000
+[tasks.ghost]
+"#,
+        )
+        .unwrap();
+
+        let report = check(&crate::load(Some(&path)).unwrap());
+        assert!(report.errors.iter().any(|e| e.starts_with("ports:")));
+        assert!(report.errors.iter().any(|e| e.starts_with("task ghost:")));
+        assert!(!report.errors.iter().any(|e| e.starts_with("task web:")));
+        assert!(report.warnings.iter().any(|w| w.starts_with("task web:")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn schema_has_no_required_keys() {
+        let schema = json_schema().to_string();
+        assert!(schema.contains("memory_limit"));
+        assert!(!schema.contains("\"required\""));
+    }
+}
@@ -0,0 +1,261 @@
+//! Where configuration comes from, lowest precedence first:
+//!
+//! 1. built-in defaults (`defaults.toml`, compiled in);
+//! 2. the config file: `--config <path>`, else `$CTF_CONFIG`, else the first
+//!    `Config.toml` found walking up from the working directory;
+//! 3. `conf.d/*.toml` beside the config file, in file name order;
+//! 4. `DATABASE_URL`, then `CTF__SECTION__KEY=value` environment variables.
+//!
+//! Secrets given as `<key>_file` or `<key>_env` are resolved last.
+
+use crate::ConfigError;
+use crate::manifest::merge;
+use crate::secret;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::{Table, Value};
+
+pub const CONFIG_FILE: &str = "Config.toml";
+pub const CONFIG_ENV: &str = "CTF_CONFIG";
+pub const FRAGMENTS_DIR: &str = "conf.d";
+pub const ENV_PREFIX: &str = "CTF__";
+
+const DEFAULTS: &str = include_str!("defaults.toml");
+
+/// Value of `--config <path>` or `--config=<path>` on the command line.
+pub fn config_arg() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Pick the config file: `explicit`, then `$CTF_CONFIG`, then a search
+/// upwards from the working directory.
+pub(crate) fn locate(explicit: Option<&Path>) -> Result<PathBuf, ConfigError> {
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
+    }
+    if let Some(path) = std::env::var_os(CONFIG_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+    let mut dir = std::env::current_dir()?;
+    loop {
+        let candidate = dir.join(CONFIG_FILE);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+        if !dir.pop() {
+            return Err(ConfigError::NotFound);
+        }
+    }
+}
+
+pub(crate) fn read_table(path: &Path) -> Result<Table, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    text.parse::<Table>().map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Defaults, the file at `path`, its `conf.d` fragments and the
+/// overrides in `vars`, merged into one table.
+pub(crate) fn layered_table(
+    path: &Path,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Table, ConfigError> {
+    let mut root: Table = DEFAULTS.parse().expect("built-in defaults are valid TOML");
+    merge(&mut root, read_table(path)?);
+
+    let fragments = path.parent().unwrap_or(Path::new(".")).join(FRAGMENTS_DIR);
+    if fragments.is_dir() {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&fragments)? {
+            let file = entry?.path();
+            if file.extension().is_some_and(|ext| ext == "toml") {
+                files.push(file);
+            }
+        }
+        files.sort();
+        for file in files {
+            merge(&mut root, read_table(&file)?);
+        }
+    }
+
+    let overrides = env_overrides(vars, &root)?;
+    merge(&mut root, overrides);
+    secret::resolve_refs(&mut root, path.parent().unwrap_or(Path::new(".")))?;
+    Ok(root)
+}
+
+/// Turn `DATABASE_URL` and `CTF__SECTION__KEY` variables into a table.
+/// Keys are lowercased and split on `__`; values are read as TOML when they
+/// parse (`true`, `10`, `["a"]`) and as plain strings otherwise. Secrets,
+/// `<key>_file` and `<key>_env` entries, and keys that already hold a string
+/// in `base` always stay strings, so `TOKEN_SECRET=123456` is not a number.
+fn env_overrides(
+    vars: impl IntoIterator<Item = (String, String)>,
+    base: &Table,
+) -> Result<Table, ConfigError> {
+    let mut vars: Vec<(String, String)> = vars.into_iter().collect();
+    // Apply the generic form last so CTF__DATABASE__URL wins over DATABASE_URL.
+    vars.sort_by_key(|(key, _)| key.starts_with(ENV_PREFIX));
+
+    let mut out = Table::new();
+    for (key, raw) in vars {
+        let path: Vec<String> = if key == "DATABASE_URL" {
+            vec!["database".into(), "url".into()]
+        } else if let Some(rest) = key.strip_prefix(ENV_PREFIX) {
+            rest.split("__").map(str::to_ascii_lowercase).collect()
+        } else {
+            continue;
+        };
+        if path.iter().any(String::is_empty) {
+            return Err(ConfigError::Invalid(format!("{}: empty key segment", key)));
+        }
+        let (leaf, sections) = path.split_last().expect("split yields at least one segment");
+
+        let mut table = &mut out;
+        for section in sections {
+            let entry = table
+                .entry(section.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            table = match entry {
+                Value::Table(t) => t,
+                _ => {
+                    return Err(ConfigError::Invalid(format!(
+                        "{}: `{}` is not a table",
+                        key, section
+                    )));
+                }
+            };
+        }
+        let value = if keeps_string(&path, base) {
+            Value::String(raw)
+        } else {
+            parse_env_value(&raw)
+        };
+        table.insert(leaf.clone(), value);
+    }
+    Ok(out)
+}
+
+/// Latest modification time of `path` and, for directories, everything
+/// below it. `None` if `path` does not exist.
+pub(crate) fn latest_modified(path: &Path) -> Option<SystemTime> {
+    let meta = fs::metadata(path).ok()?;
+    let mut latest = meta.modified().ok()?;
+    if meta.is_dir()
+        && let Ok(entries) = fs::read_dir(path)
+    {
+        for entry in entries.flatten() {
+            if let Some(t) = latest_modified(&entry.path()) {
+                latest = latest.max(t);
+            }
+        }
+    }
+    Some(latest)
+}
+
+fn keeps_string(path: &[String], base: &Table) -> bool {
+    let leaf = path.last().map(String::as_str).unwrap_or_default();
+    if secret::SECRET_KEYS.contains(&path.join(".").as_str())
+        || leaf.ends_with("_file")
+        || leaf.ends_with("_env")
+    {
+        return true;
+    }
+    let mut table = base;
+    for section in &path[..path.len() - 1] {
+        match table.get(section) {
+            Some(Value::Table(t)) => table = t,
+            _ => return false,
+        }
+    }
+    matches!(table.get(leaf), Some(Value::String(_)))
+}
+
+fn parse_env_value(raw: &str) -> Value {
+    format!("v = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn env_overrides_nest_and_parse() {
+        let base = Table::new();
+        let t = env_overrides(vars(&[
+            ("CTF__SCHEDULER__POLL_INTERVAL_SECS", "5"),
+            ("CTF__TASKS__FOO_TASK__PROTOCOL", "tcp"),
+            ("CTF__CONTAINERS__MEMORY_LIMIT", "1G"),
+            ("CTF__DATABASE__URL", "postgres://override"),
+            ("DATABASE_URL", "postgres://plain"),
+            ("PATH", "/bin"),
+        ]), &base)
+        .unwrap();
+        assert_eq!(t["scheduler"]["poll_interval_secs"].as_integer(), Some(5));
+        assert_eq!(t["tasks"]["foo_task"]["protocol"].as_str(), Some("tcp"));
+        assert_eq!(t["containers"]["memory_limit"].as_str(), Some("1G"));
+        assert_eq!(t["database"]["url"].as_str(), Some("postgres://override"));
+        assert!(!t.contains_key("path"));
+
+        assert!(env_overrides(vars(&[("CTF__SESSIONS____ADMINS", "x")]), &base).is_err());
+    }
+
+    #[test]
+    fn string_keys_stay_strings() {
+        let base: Table = "[routing]\ntraefik_domain = \"ctf.local\"\n".parse().unwrap();
+        let t = env_overrides(vars(&[
+            ("CTF__SESSIONS__TOKEN_SECRET", "123456"),
+            ("CTF__FLAGS__SECRET", "true"),
+            ("CTF__CAPTCHA__SECRET_KEY_ENV", "1"),
+            ("CTF__ROUTING__TRAEFIK_DOMAIN", "2024"),
+            ("CTF__SESSIONS__MAX_INSTANCES", "3"),
+        ]), &base)
+        .unwrap();
+        assert_eq!(t["sessions"]["token_secret"].as_str(), Some("123456"));
+        assert_eq!(t["flags"]["secret"].as_str(), Some("true"));
+        assert_eq!(t["captcha"]["secret_key_env"].as_str(), Some("1"));
+        assert_eq!(t["routing"]["traefik_domain"].as_str(), Some("2024"));
+        assert_eq!(t["sessions"]["max_instances"].as_integer(), Some(3));
+    }
+
+    #[test]
+    fn layers_apply_in_order() {
+        let dir = std::env::temp_dir().join(format!("ctf-layers-{}", std::process::id()));
+        fs::create_dir_all(dir.join(FRAGMENTS_DIR)).unwrap();
+        let path = dir.join(CONFIG_FILE);
+        fs::write(&path, "[sessions]\nmax_instances = 3\n[database]\nurl = \"file\"\n").unwrap();
+        fs::write(dir.join(FRAGMENTS_DIR).join("10-a.toml"), "[sessions]\nmax_instances = 4\n").unwrap();
+        fs::write(dir.join(FRAGMENTS_DIR).join("20-b.toml"), "[sessions]\nmax_instances = 5\n").unwrap();
+        fs::write(dir.join(FRAGMENTS_DIR).join("notes.txt"), "ignored").unwrap();
+
+        let t = layered_table(&path, vars(&[("DATABASE_URL", "env")])).unwrap();
+        assert_eq!(t["sessions"]["max_instances"].as_integer(), Some(5));
+        assert_eq!(t["sessions"]["ttl_hours"].as_integer(), Some(24));
+        assert_eq!(t["database"]["url"].as_str(), Some("env"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,40 +1,455 @@
 
-use once_cell::sync::Lazy;
+mod check;
+mod layers;
+mod manifest;
+mod secret;
+mod size;
+mod subnet;
+
+use arc_swap::ArcSwap;
+use once_cell::sync::{Lazy, OnceCell};
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::fs;
+use std::collections::{BTreeMap, HashMap};
 use thiserror::Error;
-use std::{env, path::PathBuf};
-use humantime::parse_duration;
+use std::{env, fmt, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+pub use check::{CheckReport, check, json_schema};
+pub use layers::{CONFIG_ENV, CONFIG_FILE, ENV_PREFIX, FRAGMENTS_DIR, config_arg};
+pub use manifest::{DEFAULT_TASK, MANIFEST_FILE};
+pub use secret::{SECRET_KEYS, Secret};
+pub use size::parse_byte_size;
+pub use subnet::{Cidr, SubnetPool};
+use size::{ByteSize, parse_bytes, parse_opt_bytes};
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("failed to read config file: {0}")]
     Io(#[from] std::io::Error),
+    #[error("failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: std::io::Error },
     #[error("failed to parse TOML: {0}")]
     Toml(#[from] toml::de::Error),
-    #[error("Config.toml not found in any parent directory")]
+    #[error("failed to parse {}: {source}", path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("no config file: pass --config, set CTF_CONFIG, or add Config.toml to a parent directory")]
     NotFound,
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
 }
 
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct RoutingConfig {
     pub traefik_domain: String,    // e.g. "ctf.local"
     pub http_entry: String,        // e.g. "web"
     pub tcp_entry: String,         // e.g. "tcp"
+    /// The Traefik container, attached to each instance's network. `tcp`
+    /// and `http` readiness probes run in it, so it needs `nc` and `wget`.
+    #[serde(default = "default_router")]
+    pub router_container: String,
+    /// Outbound internet access of instances; per task under `egress`.
+    #[serde(default)]
+    pub egress: Egress,
+    /// Addresses for instance networks, one `subnet_prefix` block each. Must
+    /// not overlap the daemon's `default-address-pools` or host networks.
+    #[serde(default = "default_subnet_pool")]
+    pub subnet_pool: String,
+    #[serde(default = "default_subnet_prefix")]
+    pub subnet_prefix: u8,
+}
+fn default_router() -> String { "traefik".into() }
+fn default_subnet_pool() -> String { "10.210.0.0/16".into() }
+fn default_subnet_prefix() -> u8 { 28 }
+
+impl RoutingConfig {
+    pub fn subnets(&self) -> Result<SubnetPool, String> {
+        SubnetPool::new(&self.subnet_pool, self.subnet_prefix)
+    }
+}
+
+/// Whether instance containers may open connections to the internet. They
+/// never reach other instances either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Egress {
+    #[default]
+    Allow,
+    /// Only the router and the instance's own containers are reachable.
+    Block,
 }
 
-#[derive(Deserialize)]
+/// Effective settings for one task: `[tasks._default]`, overlaid with
+/// `[tasks.<name>]`, overlaid with `tasks/<name>/task.toml`.
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 pub struct TaskConfig {
     #[serde(default="default_protocol")]
     pub protocol: String,
     #[serde(default="default_cport")]
     pub container_port: u16,
+    #[serde(default)]
+    pub flag: FlagConfig,
+    #[serde(default)]
+    pub readiness: Readiness,
+    /// Instance lifetime; falls back to `ports.default_ttl_secs`.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Time added per extension; falls back to `ports.extend_time_secs`.
+    #[serde(default)]
+    pub extend_time_secs: Option<u64>,
+    /// Extensions allowed per instance; falls back to `ports.max_extensions`.
+    #[serde(default)]
+    pub max_extensions: Option<u32>,
+    /// Cap on expiry measured from creation; falls back to
+    /// `ports.max_lifetime_secs`.
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// Stop instances idle this long; falls back to `idle.timeout_secs`.
+    /// 0 disables idle stops for the task.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Limits on this task's running instances, on top of `[capacity]`.
+    #[serde(default)]
+    pub capacity: Capacity,
+    /// Outbound access; falls back to `routing.egress`.
+    #[serde(default)]
+    pub egress: Option<Egress>,
+    /// Only nodes carrying all of these labels run the task.
+    #[serde(default)]
+    pub node_labels: HashMap<String, String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Username of the task author; becomes the task owner when synced.
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Extra environment variables for the container.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Per-task overrides of `[containers]`.
+    #[serde(default)]
+    pub containers: ContainerOverrides,
+    /// Further containers started next to the task's own for each instance,
+    /// e.g. a database or an admin bot.
+    #[serde(default)]
+    pub services: BTreeMap<String, Service>,
+    /// Latest modification time under `tasks/<name>/`, so edits to the
+    /// build context count as a change on reload.
+    #[serde(skip)]
+    pub context_modified: Option<SystemTime>,
 }
 fn default_protocol() -> String { "http".into() }
 fn default_cport()   -> u16    { 3000 }
 
-#[derive(Deserialize)]
+impl Default for TaskConfig {
+    fn default() -> Self {
+        TaskConfig {
+            protocol: default_protocol(),
+            container_port: default_cport(),
+            flag: FlagConfig::default(),
+            readiness: Readiness::default(),
+            ttl_secs: None,
+            extend_time_secs: None,
+            max_extensions: None,
+            max_lifetime_secs: None,
+            idle_timeout_secs: None,
+            capacity: Capacity::default(),
+            egress: None,
+            node_labels: HashMap::new(),
+            description: String::new(),
+            category: None,
+            author: None,
+            env: HashMap::new(),
+            containers: ContainerOverrides::default(),
+            services: BTreeMap::new(),
+            context_modified: None,
+        }
+    }
+}
+
+impl TaskConfig {
+    pub fn ttl_secs(&self, ports: &Ports) -> u64 {
+        self.ttl_secs.unwrap_or(ports.default_ttl_secs)
+    }
+
+    pub fn extend_time_secs(&self, ports: &Ports) -> u64 {
+        self.extend_time_secs.unwrap_or(ports.extend_time_secs)
+    }
+
+    pub fn max_extensions(&self, ports: &Ports) -> u32 {
+        self.max_extensions.unwrap_or(ports.max_extensions)
+    }
+
+    pub fn max_lifetime_secs(&self, ports: &Ports) -> u64 {
+        self.max_lifetime_secs.unwrap_or(ports.max_lifetime_secs)
+    }
+
+    pub fn idle_timeout_secs(&self, idle: &Idle) -> u64 {
+        self.idle_timeout_secs.unwrap_or(idle.timeout_secs)
+    }
+
+    pub fn egress(&self, routing: &RoutingConfig) -> Egress {
+        self.egress.unwrap_or(routing.egress)
+    }
+
+    fn validate(&self, name: &str) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(format!("task {}: {}", name, msg)));
+        if self.protocol != "http" && self.protocol != "tcp" {
+            return invalid(format!("protocol must be \"http\" or \"tcp\", got {:?}", self.protocol));
+        }
+        if self.container_port == 0 {
+            return invalid("container_port must be non-zero".into());
+        }
+        if self.ttl_secs == Some(0) {
+            return invalid("ttl_secs must be positive".into());
+        }
+        let r = &self.readiness;
+        if r.timeout_secs == 0 || !r.path.starts_with('/') || !(100..=599).contains(&r.status) {
+            return invalid("readiness needs timeout_secs > 0, an absolute path and a valid status".into());
+        }
+        let c = &self.containers;
+        for u in &c.ulimits {
+            if u.name.is_empty() || u.soft > u.hard {
+                return invalid(format!("ulimit {:?} needs a name and soft <= hard", u.name));
+            }
+        }
+        for path in c.tmpfs.keys().chain(&c.writable_paths) {
+            if !path.starts_with('/') {
+                return invalid(format!("mount path {:?} must be absolute", path));
+            }
+        }
+        if let Some(path) = &self.flag.file
+            && !path.starts_with('/')
+        {
+            return invalid("flag.file must be an absolute path".into());
+        }
+        for (service, s) in &self.services {
+            let valid_name = !service.is_empty()
+                && service.len() <= 32
+                && service.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid_name || service == MAIN_SERVICE {
+                return invalid(format!(
+                    "service name {:?} must be 1-32 of [a-z0-9-] and not {:?}",
+                    service, MAIN_SERVICE
+                ));
+            }
+            if s.image.is_some() == s.build.is_some() {
+                return invalid(format!("service {} needs exactly one of image and build", service));
+            }
+            if let Some(dir) = &s.build
+                && (dir.starts_with('/') || dir.split('/').any(|p| p == ".."))
+            {
+                return invalid(format!("service {}: build must be a path inside the task directory", service));
+            }
+            if s.protocol != "http" && s.protocol != "tcp" {
+                return invalid(format!("service {}: protocol must be \"http\" or \"tcp\"", service));
+            }
+            if s.expose && s.container_port == 0 {
+                return invalid(format!("service {}: container_port must be non-zero", service));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Network alias of a task's own container, under which its services
+/// reach it.
+pub const MAIN_SERVICE: &str = "app";
+
+/// A further container of a multi-container task. All containers of an
+/// instance share a private network on which each is reachable by its
+/// service name, and the task's own container as [`MAIN_SERVICE`].
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct Service {
+    /// Image to pull, e.g. `redis:7-alpine`.
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Directory under `tasks/<name>/` to build the image from instead.
+    #[serde(default)]
+    pub build: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Route the service through Traefik at its own hostname.
+    #[serde(default)]
+    pub expose: bool,
+    #[serde(default="default_protocol")]
+    pub protocol: String,
+    #[serde(default="default_cport")]
+    pub container_port: u16,
+    /// Hand the instance flag to this container too, as `flag.env` and
+    /// `flag.file` do for the task's own.
+    #[serde(default)]
+    pub flag: bool,
+    /// Overrides of `[containers]` for this service.
+    #[serde(default)]
+    pub containers: ContainerOverrides,
+}
+
+/// Per-task overrides of [`ContainerConfig`] plus settings that only make
+/// sense per task. Unset fields inherit the global `[containers]` values.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, JsonSchema)]
+pub struct ContainerOverrides {
+    #[serde(default, deserialize_with = "parse_opt_bytes")]
+    #[schemars(with = "Option<ByteSize>")]
+    pub memory_limit: Option<i64>,
+    #[serde(default, deserialize_with = "parse_opt_bytes")]
+    #[schemars(with = "Option<ByteSize>")]
+    pub swap_limit: Option<i64>,
+    #[serde(default)]
+    pub cpu_quota: Option<f64>,
+    #[serde(default)]
+    pub pids_limit: Option<u64>,
+    #[serde(default)]
+    pub enable_no_new_privileges: Option<bool>,
+    #[serde(default)]
+    pub read_only_rootfs: Option<bool>,
+    #[serde(default)]
+    pub enable_tmpfs: Option<bool>,
+    #[serde(default, deserialize_with = "parse_opt_bytes")]
+    #[schemars(with = "Option<ByteSize>")]
+    pub tmpfs_size: Option<i64>,
+    #[serde(default)]
+    pub drop_all_capabilities: Option<bool>,
+    /// Replaces the global list when set.
+    #[serde(default)]
+    pub add_capabilities: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub ulimits: Vec<Ulimit>,
+    /// Extra tmpfs mounts: container path to mount options, e.g. `"rw,size=16m"`.
+    #[serde(default)]
+    pub tmpfs: HashMap<String, String>,
+    /// Container paths backed by a fresh writable volume, removed with the
+    /// container. Useful for web tasks under a read-only root filesystem.
+    #[serde(default)]
+    pub writable_paths: Vec<String>,
+    /// Extra `--security-opt` entries, e.g. `"seccomp=unconfined"`.
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct Ulimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+impl ContainerConfig {
+    /// Effective container settings for a task with `o` applied.
+    pub fn with_overrides(&self, o: &ContainerOverrides) -> ContainerConfig {
+        ContainerConfig {
+            memory_limit: o.memory_limit.unwrap_or(self.memory_limit),
+            swap_limit: o.swap_limit.unwrap_or(self.swap_limit),
+            cpu_quota: o.cpu_quota.unwrap_or(self.cpu_quota),
+            pids_limit: o.pids_limit.unwrap_or(self.pids_limit),
+            enable_no_new_privileges: o
+                .enable_no_new_privileges
+                .unwrap_or(self.enable_no_new_privileges),
+            read_only_rootfs: o.read_only_rootfs.unwrap_or(self.read_only_rootfs),
+            enable_tmpfs: o.enable_tmpfs.unwrap_or(self.enable_tmpfs),
+            tmpfs_size: o.tmpfs_size.unwrap_or(self.tmpfs_size),
+            drop_all_capabilities: o.drop_all_capabilities.unwrap_or(self.drop_all_capabilities),
+            add_capabilities: o
+                .add_capabilities
+                .clone()
+                .unwrap_or_else(|| self.add_capabilities.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagMode {
+    /// No per-instance flag; the image carries its own.
+    #[default]
+    Static,
+    /// HMAC of the owner (user or team) and task, stable across redeploys.
+    Hmac,
+    /// Fresh random flag for every instance.
+    Random,
+}
+
+/// How a task receives its per-instance flag.
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct FlagConfig {
+    #[serde(default)]
+    pub mode: FlagMode,
+    /// Flags look like `<prefix>{<hex>}`.
+    #[serde(default = "default_flag_prefix")]
+    pub prefix: String,
+    /// Environment variable the flag is exported as.
+    #[serde(default = "default_flag_env")]
+    pub env: Option<String>,
+    /// Absolute path the flag is written to before the container starts.
+    /// The path must be writable, so this needs a writable root filesystem.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Flag accepted by `/submit` in addition to the per-instance one.
+    #[serde(default)]
+    pub value: Option<String>,
+}
+fn default_flag_prefix() -> String { "CTF".into() }
+fn default_flag_env() -> Option<String> { Some("FLAG".into()) }
+
+/// What a new instance's container must pass before the instance is
+/// handed out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Probe {
+    /// Ready once started.
+    #[default]
+    None,
+    /// `container_port` accepts connections.
+    Tcp,
+    /// A GET of `path` on `container_port` answers `status`.
+    Http,
+    /// The image's `HEALTHCHECK` reports healthy.
+    Docker,
+}
+
+/// Readiness check of a task's own container after it starts.
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct Readiness {
+    #[serde(default)]
+    pub probe: Probe,
+    #[serde(default = "default_probe_path")]
+    pub path: String,
+    #[serde(default = "default_probe_status")]
+    pub status: u16,
+    /// The deploy fails when the probe has not passed after this long.
+    #[serde(default = "default_probe_timeout")]
+    pub timeout_secs: u64,
+}
+fn default_probe_path() -> String { "/".into() }
+fn default_probe_status() -> u16 { 200 }
+fn default_probe_timeout() -> u64 { 60 }
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness {
+            probe: Probe::default(),
+            path: default_probe_path(),
+            status: default_probe_status(),
+            timeout_secs: default_probe_timeout(),
+        }
+    }
+}
+
+impl Default for FlagConfig {
+    fn default() -> Self {
+        FlagConfig {
+            mode: FlagMode::default(),
+            prefix: default_flag_prefix(),
+            env: default_flag_env(),
+            file: None,
+            value: None,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
 pub struct Config {
     pub routing: RoutingConfig,
     pub tasks: std::collections::HashMap<String, TaskConfig>,
@@ -44,59 +459,285 @@ pub struct Config {
     pub scheduler: Scheduler,
     pub sessions: Sessions,
     pub containers: ContainerConfig,
+    #[serde(default)]
+    pub flags: Flags,
+    #[serde(default)]
+    pub submissions: Submissions,
+    pub reload: Reload,
+    pub notifications: Notifications,
+    pub idle: Idle,
+    pub capacity: Capacity,
+    pub queue: Queue,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    /// The config file this was loaded from; reloads read it again.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Directory holding the task build contexts, beside the config file.
+    #[serde(skip)]
+    pub tasks_dir: PathBuf,
+}
+
+impl Config {
+    /// Settings for `name`, or `_default` for tasks not in the config.
+    pub fn task(&self, name: &str) -> &TaskConfig {
+        self.tasks.get(name).unwrap_or(&self.tasks[DEFAULT_TASK])
+    }
+
+    /// Whether `name` is a deployable task (and not `_default`).
+    pub fn has_task(&self, name: &str) -> bool {
+        name != DEFAULT_TASK && self.tasks.contains_key(name)
+    }
+
+    /// The Docker nodes to deploy on: `[[nodes]]`, or the local daemon as
+    /// [`LOCAL_NODE`] when none are configured.
+    pub fn nodes(&self) -> &[Node] {
+        if self.nodes.is_empty() { &LOCAL } else { &self.nodes }
+    }
+
+    /// `name` among [`Config::nodes`]. [`LOCAL_NODE`] is always known, even
+    /// once `[[nodes]]` leaves it out, so instances deployed before nodes
+    /// were configured can still be stopped and reconciled.
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes().iter().find(|n| n.name == name).or_else(|| LOCAL.iter().find(|n| n.name == name))
+    }
+
+    /// The local daemon, when `[[nodes]]` no longer lists it. Nothing is
+    /// placed there; it is only cleaned up.
+    pub fn implicit_local_node(&self) -> Option<&Node> {
+        if self.nodes().iter().any(|n| n.name == LOCAL_NODE) { None } else { LOCAL.first() }
+    }
+
+    /// The Traefik container on `node`.
+    pub fn router_container(&self, node: &str) -> &str {
+        self.node(node)
+            .and_then(|n| n.router.as_deref())
+            .unwrap_or(&self.routing.router_container)
+    }
+}
+
+/// Rate limit for wrong flag submissions, per user and task.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct Submissions {
+    #[serde(default = "default_max_wrong")]
+    pub max_wrong: u32,
+    #[serde(default = "default_wrong_window_secs")]
+    pub window_secs: u64,
 }
+fn default_max_wrong() -> u32 { 5 }
+fn default_wrong_window_secs() -> u64 { 60 }
 
-#[derive(Debug, Deserialize)]
+impl Default for Submissions {
+    fn default() -> Self {
+        Submissions {
+            max_wrong: default_max_wrong(),
+            window_secs: default_wrong_window_secs(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct Flags {
+    /// Key for `hmac` flag mode.
+    #[serde(default)]
+    pub secret: Secret,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Ports {
     pub min: u16,
     pub max: u16,
     pub default_ttl_secs: u64,
+    /// Time each player extension adds to the current expiry.
     pub extend_time_secs: u64,
+    /// Extensions a player may make per instance.
+    pub max_extensions: u32,
+    /// No extension pushes expiry past creation time plus this.
+    pub max_lifetime_secs: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Scheduler {
     pub poll_interval_secs: u64,
+    /// How often to compare containers with instance rows.
+    pub reconcile_interval_secs: u64,
+    /// Containers younger than this are never treated as orphans, so a
+    /// deploy that has not written its row yet is left alone.
+    pub orphan_grace_secs: u64,
+    /// Leadership lease among scheduler replicas; a dead leader is replaced
+    /// within about this long.
+    pub lease_ttl_secs: u64,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Database {
+/// Limits on running instances, globally in `[capacity]` or per task in
+/// `[tasks.<name>.capacity]`. Memory and CPU add up the `[containers]`
+/// limits of each instance. 0 means no limit.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, JsonSchema)]
+pub struct Capacity {
+    #[serde(default)]
+    pub max_instances: u32,
+    #[serde(default, deserialize_with = "parse_bytes")]
+    #[schemars(with = "ByteSize")]
+    pub max_memory: i64,
+    /// Cores, summed over `cpu_quota`.
+    #[serde(default)]
+    pub max_cpus: f64,
+}
+
+/// Name of the implicit node when `[[nodes]]` is empty, and of the node
+/// instances created before multi-host support ran on.
+pub const LOCAL_NODE: &str = "local";
+
+static LOCAL: Lazy<Vec<Node>> = Lazy::new(|| {
+    vec![Node {
+        name: LOCAL_NODE.into(),
+        url: String::new(),
+        tls: None,
+        labels: HashMap::new(),
+        capacity: Capacity::default(),
+        drain: false,
+        router: None,
+    }]
+});
+
+/// A Docker daemon instances can be placed on.
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct Node {
+    pub name: String,
+    /// `unix:///path`, `tcp://host:port` (with `tls` for TLS) or
+    /// `ssh://user@host`; empty for the local daemon's defaults.
+    #[serde(default)]
     pub url: String,
+    #[serde(default)]
+    pub tls: Option<NodeTls>,
+    /// Matched against `node_labels` of tasks.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Limits on instances placed on this node; 0 means no limit.
+    #[serde(default)]
+    pub capacity: Capacity,
+    /// Place no new instances here; running ones stay until they stop.
+    #[serde(default)]
+    pub drain: bool,
+    /// The Traefik container on this node, if not `routing.router_container`.
+    #[serde(default)]
+    pub router: Option<String>,
+}
+
+/// Client certificate for a `tcp://` node, as for `docker --tlsverify`.
+#[derive(Clone, Debug, PartialEq, Deserialize, JsonSchema)]
+pub struct NodeTls {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Deploys waiting for capacity. Each user holds at most one place.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Queue {
+    /// Deploys refused once this many are waiting.
+    pub max_length: u32,
+    /// Waiting deploys are dropped after this long.
+    pub timeout_secs: u64,
+}
+
+/// Stopping instances nobody uses. Activity is the average rate between two
+/// samples of the container's Docker stats.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Idle {
+    /// Stop instances without activity for this long; 0 disables.
+    pub timeout_secs: u64,
+    /// Network traffic (received plus sent) per second that counts as use.
+    #[serde(deserialize_with = "parse_bytes")]
+    #[schemars(with = "ByteSize")]
+    pub min_net_bytes: i64,
+    /// CPU time per second that counts as use.
+    pub min_cpu_millis: u64,
+}
+
+/// Hot reload. SIGHUP always reloads; `watch` also polls the config file,
+/// `conf.d` and `tasks/` for changes every `interval_secs`.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Reload {
+    pub watch: bool,
+    pub interval_secs: u64,
+}
+
+/// Warnings sent before instances expire.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Notifications {
+    /// Warn when this many seconds or fewer remain; one warning per threshold.
+    pub expiry_warning_secs: Vec<u64>,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+}
+
+/// An outbound webhook receiving instance events.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct Webhook {
+    /// Endpoint URL; Discord and Slack URLs embed a token, hence secret.
+    pub url: Secret,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Extra attempts after a failed delivery, with doubling backoff.
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+}
+fn default_webhook_retries() -> u32 { 3 }
+fn default_webhook_timeout_secs() -> u64 { 5 }
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// The event as a JSON object.
+    #[default]
+    Json,
+    /// `{"content": ...}` for Discord webhooks.
+    Discord,
+    /// `{"text": ...}` for Slack incoming webhooks.
+    Slack,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Database {
+    /// Connection URL, including the password.
+    pub url: Secret,
 }
 
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Sessions {
     pub ttl_hours: i64,
     pub max_instances: u16,
+    /// Running team-owned instances allowed per team.
+    #[serde(default = "default_max_team_instances")]
+    pub max_team_instances: u16,
+    /// Key for hashing session tokens before they are stored.
+    pub token_secret: Secret,
+    /// Usernames promoted to the admin role at gateway startup.
+    #[serde(default)]
+    pub admins: Vec<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Captcha {
     pub provider: String,
     pub site_key: String,
-    pub secret_key: String,
+    pub secret_key: Secret,
     pub verify_url: String,
 }
 
-fn find_config_file() -> Result<PathBuf, ConfigError> {
-    let mut dir = env::current_dir()?;
-    loop {
-        let candidate = dir.join("Config.toml");
-        if candidate.is_file() {
-            return Ok(candidate);
-        }
-        if !dir.pop() {
-            break;
-        }
-    }
-    Err(ConfigError::NotFound)
-}
-#[derive(Deserialize)]
+fn default_max_team_instances() -> u16 { 4 }
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct ContainerConfig {
     #[serde(deserialize_with = "parse_bytes")]
+    #[schemars(with = "ByteSize")]
     pub memory_limit:    i64,
     #[serde(deserialize_with = "parse_bytes")]
+    #[schemars(with = "ByteSize")]
     pub swap_limit:      i64,
     pub cpu_quota:       f64,        // cores
     pub pids_limit:      u64,
@@ -105,6 +746,7 @@ pub struct ContainerConfig {
     pub read_only_rootfs:         bool,
     pub enable_tmpfs:             bool,
     #[serde(deserialize_with = "parse_bytes")]
+    #[schemars(with = "ByteSize")]
     pub tmpfs_size:       i64,
 
     pub drop_all_capabilities: bool,
@@ -112,24 +754,222 @@ pub struct ContainerConfig {
     pub add_capabilities:   Vec<String>,
 }
 
-fn parse_bytes<'de, D>(deserializer: D) -> Result<i64, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    let dur = parse_duration(&s)
-        .map_err(serde::de::Error::custom)?;
-    Ok(dur.as_secs() as i64)  // bytes interpreted as seconds numerically
+/// Docker refuses memory limits below 6 MiB.
+const MIN_MEMORY_BYTES: i64 = 6 << 20;
+
+fn mib(bytes: i64) -> String {
+    format!("{} MiB", bytes >> 20)
 }
 
-static CONFIG: Lazy<Config> = Lazy::new(|| {
-    let path = find_config_file().expect("Config.toml not found");
-    let toml_str = fs::read_to_string(&path)
-        .unwrap_or_else(|e| panic!("unable to read {}: {}", path.display(), e));
-    toml::from_str(&toml_str)
-        .unwrap_or_else(|e| panic!("invalid TOML in {}: {}", path.display(), e))
-});
+impl ContainerConfig {
+    /// Check limits for sanity; `section` names the table in error messages.
+    pub fn validate(&self, section: &str) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(format!("{}.{}", section, msg)));
+        if self.memory_limit < MIN_MEMORY_BYTES {
+            return invalid(format!(
+                "memory_limit ({} bytes) must be at least {}",
+                self.memory_limit,
+                mib(MIN_MEMORY_BYTES)
+            ));
+        }
+        if self.swap_limit < self.memory_limit {
+            return invalid(format!(
+                "swap_limit ({}) must be at least memory_limit ({})",
+                mib(self.swap_limit),
+                mib(self.memory_limit)
+            ));
+        }
+        if self.enable_tmpfs && self.tmpfs_size <= 0 {
+            return invalid("tmpfs_size must be positive when enable_tmpfs is set".into());
+        }
+        if self.cpu_quota <= 0.0 {
+            return invalid(format!("cpu_quota ({}) must be positive", self.cpu_quota));
+        }
+        if self.pids_limit == 0 {
+            return invalid("pids_limit must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// Load the layered configuration (see [`layers`]) for the config file
+/// at `explicit`, or the one found through `CTF_CONFIG` or the working
+/// directory. Task manifests are read from the `tasks/` directory beside it.
+pub fn load(explicit: Option<&Path>) -> Result<Config, ConfigError> {
+    load_from(layers::locate(explicit)?)
+}
+
+fn load_from(path: PathBuf) -> Result<Config, ConfigError> {
+    let root = layers::layered_table(&path, env::vars())?;
+    let tasks_dir = path.parent().unwrap_or(Path::new(".")).join("tasks");
+    let mut cfg = build(root, &tasks_dir)?;
+    cfg.path = path;
+    cfg.tasks_dir = tasks_dir;
+    Ok(cfg)
+}
+
+fn build(mut root: toml::Table, tasks_dir: &Path) -> Result<Config, ConfigError> {
+    manifest::resolve_tasks(&mut root, tasks_dir)?;
+    let mut cfg: Config = toml::Value::Table(root).try_into()?;
+    cfg.containers.validate("containers")?;
+    for (name, task) in &cfg.tasks {
+        task.validate(name)?;
+        cfg.containers
+            .with_overrides(&task.containers)
+            .validate(&format!("tasks.{}.containers", name))?;
+    }
+    if !cfg.tasks.contains_key(DEFAULT_TASK) {
+        return Err(ConfigError::Invalid("[tasks._default] is required".into()));
+    }
+    for (name, task) in cfg.tasks.iter_mut() {
+        task.context_modified = layers::latest_modified(&tasks_dir.join(name));
+    }
+    Ok(cfg)
+}
+
+/// Latest modification time of anything a reload would read: the config
+/// file, `conf.d` and the `tasks/` directory.
+pub fn sources_modified(cfg: &Config) -> Option<SystemTime> {
+    let fragments = cfg.path.parent().unwrap_or(Path::new(".")).join(FRAGMENTS_DIR);
+    [cfg.path.as_path(), &fragments, &cfg.tasks_dir]
+        .into_iter()
+        .filter_map(layers::latest_modified)
+        .max()
+}
 
-pub fn get_config() -> &'static Config {
-    &CONFIG
-}
\ No newline at end of file
+/// Tasks that differ between two configs, by name.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Subset of `changed` whose build context changed, so the existing
+    /// image is stale.
+    pub rebuild: Vec<String>,
+}
+
+impl ReloadReport {
+    pub fn diff(old: &Config, new: &Config) -> Self {
+        let mut report = ReloadReport::default();
+        for (name, task) in &new.tasks {
+            match old.tasks.get(name) {
+                None => report.added.push(name.clone()),
+                Some(prev) if prev != task => {
+                    report.changed.push(name.clone());
+                    if prev.context_modified != task.context_modified {
+                        report.rebuild.push(name.clone());
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        report.removed = old
+            .tasks
+            .keys()
+            .filter(|name| !new.tasks.contains_key(*name))
+            .cloned()
+            .collect();
+        for list in [&mut report.added, &mut report.changed, &mut report.removed, &mut report.rebuild] {
+            list.sort();
+        }
+        report
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tasks added {:?}, changed {:?}, removed {:?}",
+            self.added, self.changed, self.removed
+        )
+    }
+}
+
+static CONFIG: OnceCell<ArcSwap<Config>> = OnceCell::new();
+
+fn current(explicit: Option<&Path>) -> Result<&'static ArcSwap<Config>, ConfigError> {
+    CONFIG.get_or_try_init(|| load(explicit).map(ArcSwap::from_pointee))
+}
+
+/// Load the configuration for the process. Binaries call this at startup
+/// so a bad config, including placeholder secrets, is reported instead of
+/// panicking later.
+pub fn init(explicit: Option<&Path>) -> Result<Arc<Config>, ConfigError> {
+    let cfg = current(explicit)?.load_full();
+    secret::refuse_placeholders(&cfg)?;
+    Ok(cfg)
+}
+
+/// Re-read the config file, `conf.d`, the environment and `tasks/`. The new
+/// config is validated in full before it replaces the current one; on error
+/// the current config stays in place.
+pub fn reload() -> Result<ReloadReport, ConfigError> {
+    let slot = current(None)?;
+    let old = slot.load_full();
+    let new = load_from(old.path.clone())?;
+    secret::refuse_placeholders(&new)?;
+    let report = ReloadReport::diff(&old, &new);
+    slot.store(Arc::new(new));
+    Ok(report)
+}
+
+/// The current configuration; loads it on first use if [`init`] was not
+/// called. Hold on to the returned `Arc` for a consistent view across a
+/// request, since a reload may swap the config at any time.
+pub fn get_config() -> Arc<Config> {
+    current(None)
+        .map(ArcSwap::load_full)
+        .unwrap_or_else(|e| panic!("invalid config: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const BASE: &str = r#"
+[routing]
+traefik_domain = "ctf.test"
+[database]
+url = "postgres://test"
+[captcha]
+provider = "none"
+site_key = ""
+secret_key = ""
+verify_url = ""
+[sessions]
+token_secret = "test"
+[tasks.kept]
+ttl_secs = 60
+[tasks.edited]
+ttl_secs = 60
+[tasks.dropped]
+"#;
+
+    #[test]
+    fn reload_report_lists_task_changes() {
+        let dir = env::temp_dir().join(format!("ctf-reload-{}", std::process::id()));
+        fs::create_dir_all(dir.join("tasks/kept")).unwrap();
+        let path = dir.join(CONFIG_FILE);
+        fs::write(&path, BASE).unwrap();
+        let old = load(Some(&path)).unwrap();
+
+        let edited = BASE
+            .replace("[tasks.edited]\nttl_secs = 60", "[tasks.edited]\nttl_secs = 90")
+            .replace("[tasks.dropped]", "[tasks.fresh]");
+        fs::write(&path, edited).unwrap();
+        let new = load(Some(&path)).unwrap();
+
+        let report = ReloadReport::diff(&old, &new);
+        assert_eq!(report.added, vec!["fresh"]);
+        assert_eq!(report.changed, vec!["edited"]);
+        assert_eq!(report.removed, vec!["dropped"]);
+        assert!(report.rebuild.is_empty());
+
+        fs::write(&path, BASE.replace("ttl_secs = 60", "ttl_secs = 0")).unwrap();
+        assert!(matches!(load(Some(&path)), Err(ConfigError::Invalid(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Per-task `task.toml` manifests living next to each task's Dockerfile.
+
+use crate::ConfigError;
+use std::fs;
+use std::path::Path;
+use toml::{Table, Value};
+
+pub const MANIFEST_FILE: &str = "task.toml";
+pub const DEFAULT_TASK: &str = "_default";
+
+/// Recursively overlay `over` onto `base`; tables merge, everything else replaces.
+pub(crate) fn merge(base: &mut Table, over: Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(b)), Value::Table(o)) => merge(b, o),
+            (_, v) => {
+                base.insert(key, v);
+            }
+        }
+    }
+}
+
+fn read_manifest(path: &Path) -> Result<Table, ConfigError> {
+    let text = fs::read_to_string(path)?;
+    text.parse::<Table>()
+        .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e)))
+}
+
+/// Rewrite `[tasks]` in `root` so each task is `_default`, overlaid with its
+/// `[tasks.<name>]` entry, overlaid with `tasks_dir/<name>/task.toml`.
+/// Task directories without a config entry are picked up as well.
+pub(crate) fn resolve_tasks(root: &mut Table, tasks_dir: &Path) -> Result<(), ConfigError> {
+    let mut declared = match root.remove("tasks") {
+        Some(Value::Table(t)) => t,
+        Some(_) => return Err(ConfigError::Invalid("`tasks` must be a table".into())),
+        None => Table::new(),
+    };
+    let defaults = match declared.get(DEFAULT_TASK) {
+        Some(Value::Table(t)) => t.clone(),
+        _ => Table::new(),
+    };
+
+    let mut names: Vec<String> = declared.keys().cloned().collect();
+    if tasks_dir.is_dir() {
+        for entry in fs::read_dir(tasks_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir()
+                && let Ok(name) = entry.file_name().into_string()
+                && !names.contains(&name)
+            {
+                names.push(name);
+            }
+        }
+    }
+
+    let mut resolved = Table::new();
+    for name in names {
+        let mut task = defaults.clone();
+        if name != DEFAULT_TASK {
+            if let Some(Value::Table(entry)) = declared.remove(&name) {
+                merge(&mut task, entry);
+            }
+            let manifest = tasks_dir.join(&name).join(MANIFEST_FILE);
+            if manifest.is_file() {
+                merge(&mut task, read_manifest(&manifest)?);
+            }
+        }
+        resolved.insert(name, Value::Table(task));
+    }
+    root.insert("tasks".into(), Value::Table(resolved));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_overrides_config_and_defaults() {
+        let dir = std::env::temp_dir().join(format!("ctf-manifest-{}", std::process::id()));
+        fs::create_dir_all(dir.join("web1")).unwrap();
+        fs::create_dir_all(dir.join("pwn1")).unwrap();
+        fs::write(
+            dir.join("web1").join(MANIFEST_FILE),
+            "container_port = 8080\n[flag]\nmode = \"random\"\n",
+        )
+        .unwrap();
+
+        let mut root: Table = r#"
+            [tasks._default]
+            protocol = "http"
+            container_port = 3000
+            [tasks._default.flag]
+            prefix = "CTF"
+            [tasks.web1]
+            protocol = "tcp"
+        "#
+        .parse()
+        .unwrap();
+        resolve_tasks(&mut root, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let tasks = root["tasks"].as_table().unwrap();
+        let web1 = tasks["web1"].as_table().unwrap();
+        assert_eq!(web1["protocol"].as_str(), Some("tcp"));
+        assert_eq!(web1["container_port"].as_integer(), Some(8080));
+        assert_eq!(web1["flag"]["mode"].as_str(), Some("random"));
+        assert_eq!(web1["flag"]["prefix"].as_str(), Some("CTF"));
+
+        // Directory without a config entry inherits the defaults.
+        let pwn1 = tasks["pwn1"].as_table().unwrap();
+        assert_eq!(pwn1["container_port"].as_integer(), Some(3000));
+    }
+}
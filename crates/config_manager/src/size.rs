@@ -0,0 +1,91 @@
+//! Byte sizes such as `"512M"`, `"1Gi"`, `"64MB"` or a plain integer.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Parse a byte size. `K`/`M`/`G` and `Ki`/`Mi`/`Gi` are powers of 1024, as
+/// in `docker run --memory`; `KB`/`MB`/`GB` are powers of 1000. A trailing
+/// `B` is allowed on the binary forms (`MiB`), and suffixes are case-insensitive.
+pub fn parse_byte_size(s: &str) -> Result<i64, String> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (digits, suffix) = trimmed.split_at(split);
+    if digits.is_empty() {
+        return Err(format!("invalid byte size {:?}: expected a number", s));
+    }
+    let value: i64 = digits
+        .parse()
+        .map_err(|_| format!("invalid byte size {:?}: number too large", s))?;
+
+    let multiplier: i64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "ki" | "kib" => 1 << 10,
+        "m" | "mi" | "mib" => 1 << 20,
+        "g" | "gi" | "gib" => 1 << 30,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        other => {
+            return Err(format!(
+                "invalid byte size {:?}: unknown unit {:?} (use K, M, G, Ki, Mi, Gi, KB, MB or GB)",
+                s, other
+            ));
+        }
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid byte size {:?}: too large", s))
+}
+
+/// A byte count, or a size string such as `"512M"` or `"1Gi"`.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum ByteSize {
+    Int(i64),
+    Str(String),
+}
+
+pub(crate) fn parse_bytes<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match ByteSize::deserialize(deserializer)? {
+        ByteSize::Int(n) if n >= 0 => Ok(n),
+        ByteSize::Int(n) => Err(serde::de::Error::custom(format!("byte size {} is negative", n))),
+        ByteSize::Str(s) => parse_byte_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+pub(crate) fn parse_opt_bytes<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    parse_bytes(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_byte_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_byte_size("512Mi"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_byte_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_byte_size("64k"), Ok(64 * 1024));
+        assert_eq!(parse_byte_size("64KB"), Ok(64_000));
+        assert_eq!(parse_byte_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_byte_size(" 2 G "), Ok(2 << 30));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse_byte_size("").is_err());
+        assert!(parse_byte_size("M").is_err());
+        assert!(parse_byte_size("12T").is_err());
+        assert!(parse_byte_size("1.5G").is_err());
+        assert!(parse_byte_size("99999999999999G").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! IPv4 subnets for instance networks, carved out of `routing.subnet_pool`
+//! instead of Docker's default address pools, which run out after a few
+//! dozen bridge networks.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// Longest prefix handed out; a /29 still leaves six addresses.
+pub const MAX_SUBNET_PREFIX: u8 = 29;
+
+/// An IPv4 block such as `10.210.0.0/16`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: u32,
+    prefix: u8,
+}
+
+fn mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+impl Cidr {
+    /// Parse `a.b.c.d/n`; the address must be the start of the block.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = s.split_once('/').ok_or_else(|| format!("invalid subnet {:?}: expected a.b.c.d/n", s))?;
+        let addr: Ipv4Addr = addr.parse().map_err(|_| format!("invalid subnet {:?}: bad address", s))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= 32)
+            .ok_or_else(|| format!("invalid subnet {:?}: prefix must be 0 to 32", s))?;
+        let addr = u32::from(addr);
+        if addr & !mask(prefix) != 0 {
+            return Err(format!("invalid subnet {:?}: host bits are set", s));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn overlaps(&self, other: &Cidr) -> bool {
+        (self.addr ^ other.addr) & mask(self.prefix.min(other.prefix)) == 0
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.addr), self.prefix)
+    }
+}
+
+/// The blocks of one prefix length inside a pool, in address order.
+#[derive(Clone, Copy, Debug)]
+pub struct SubnetPool {
+    pool: Cidr,
+    prefix: u8,
+}
+
+impl SubnetPool {
+    pub fn new(pool: &str, prefix: u8) -> Result<Self, String> {
+        let pool = Cidr::parse(pool)?;
+        if prefix < pool.prefix || prefix > MAX_SUBNET_PREFIX {
+            return Err(format!(
+                "subnet prefix /{} must lie between the pool's /{} and /{}",
+                prefix, pool.prefix, MAX_SUBNET_PREFIX
+            ));
+        }
+        Ok(SubnetPool { pool, prefix })
+    }
+
+    /// How many subnets, i.e. instance networks per node, the pool holds.
+    pub fn size(&self) -> u64 {
+        1 << (self.prefix - self.pool.prefix)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cidr> + '_ {
+        let step = 1u64 << (32 - self.prefix);
+        (0..self.size()).map(move |i| Cidr { addr: self.pool.addr + (i * step) as u32, prefix: self.prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pools_split_into_disjoint_subnets() {
+        let pool = SubnetPool::new("10.210.0.0/16", 28).unwrap();
+        assert_eq!(pool.size(), 4096);
+        let first: Vec<String> = pool.iter().take(2).map(|c| c.to_string()).collect();
+        assert_eq!(first, ["10.210.0.0/28", "10.210.0.16/28"]);
+        assert_eq!(pool.iter().last().unwrap().to_string(), "10.210.255.240/28");
+
+        let docker0 = Cidr::parse("172.17.0.0/16").unwrap();
+        assert!(pool.iter().all(|c| !c.overlaps(&docker0)));
+        let wide = Cidr::parse("10.210.0.0/24").unwrap();
+        assert_eq!(pool.iter().filter(|c| c.overlaps(&wide)).count(), 16);
+
+        assert!(Cidr::parse("10.210.0.1/16").is_err());
+        assert!(SubnetPool::new("10.210.0.0/16", 30).is_err());
+        assert!(SubnetPool::new("10.210.0.0/16", 12).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+-- Existing rows hold plaintext tokens; they cannot be rehashed without the
+-- application key, so every session is invalidated.
+DELETE FROM sessions;
+ALTER TABLE sessions RENAME COLUMN id TO token_hash;
@@ -0,0 +1,4 @@
+ALTER TABLE users
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'player';  -- 'player','author','admin'
+ALTER TABLE tasks
+    ADD COLUMN owner_id INT REFERENCES users(id);
@@ -0,0 +1,11 @@
+ALTER TABLE users
+    ADD COLUMN banned BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE audit_log (
+                           id SERIAL PRIMARY KEY,
+                           actor_id INT NOT NULL REFERENCES users(id),
+                           action TEXT NOT NULL,
+                           target TEXT NOT NULL,
+                           detail TEXT NOT NULL DEFAULT '',
+                           created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+ALTER TABLE instances DROP COLUMN team_id;
+ALTER TABLE users DROP COLUMN team_id;
+DROP TABLE teams;
@@ -0,0 +1,14 @@
+CREATE TABLE teams (
+                       id SERIAL PRIMARY KEY,
+                       name TEXT UNIQUE NOT NULL,
+                       join_code TEXT NOT NULL,
+                       created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- A user belongs to at most one team.
+ALTER TABLE users
+    ADD COLUMN team_id INT REFERENCES teams(id);
+
+-- Team-owned instances are visible to and controllable by every member.
+ALTER TABLE instances
+    ADD COLUMN team_id INT REFERENCES teams(id);
@@ -0,0 +1,11 @@
+CREATE TABLE api_keys (
+                          id SERIAL PRIMARY KEY,
+                          user_id INT NOT NULL REFERENCES users(id),
+                          name TEXT NOT NULL,
+                          key_hash TEXT UNIQUE NOT NULL,
+                          scopes TEXT NOT NULL DEFAULT '',  -- comma separated; empty means all
+                          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                          expires_at TIMESTAMPTZ,
+                          revoked_at TIMESTAMPTZ,
+                          UNIQUE (user_id, name)
+);
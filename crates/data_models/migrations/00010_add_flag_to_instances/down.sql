@@ -0,0 +1,2 @@
+DROP INDEX instances_flag_idx;
+ALTER TABLE instances DROP COLUMN flag;
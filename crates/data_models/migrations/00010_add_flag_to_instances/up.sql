@@ -0,0 +1,3 @@
+ALTER TABLE instances
+    ADD COLUMN flag TEXT;
+CREATE INDEX instances_flag_idx ON instances (flag);
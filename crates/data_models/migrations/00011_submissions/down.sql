@@ -0,0 +1,2 @@
+DROP TABLE solves;
+DROP TABLE submissions;
@@ -0,0 +1,20 @@
+CREATE TABLE submissions (
+                             id SERIAL PRIMARY KEY,
+                             user_id INT NOT NULL REFERENCES users(id),
+                             team_id INT REFERENCES teams(id),
+                             task_name TEXT NOT NULL REFERENCES tasks(name),
+                             submitted TEXT NOT NULL,
+                             correct BOOLEAN NOT NULL,
+                             -- set when the flag belongs to someone else's instance
+                             shared_from_instance INT REFERENCES instances(id),
+                             created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX submissions_user_task_idx ON submissions (user_id, task_name, created_at);
+
+CREATE TABLE solves (
+                        user_id INT NOT NULL REFERENCES users(id),
+                        task_name TEXT NOT NULL REFERENCES tasks(name),
+                        team_id INT REFERENCES teams(id),
+                        solved_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        PRIMARY KEY (user_id, task_name)
+);
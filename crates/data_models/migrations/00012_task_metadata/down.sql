@@ -0,0 +1,5 @@
+ALTER TABLE tasks
+    DROP COLUMN author,
+    DROP COLUMN category,
+    DROP COLUMN description,
+    DROP COLUMN protocol;
@@ -0,0 +1,5 @@
+ALTER TABLE tasks
+    ADD COLUMN protocol TEXT NOT NULL DEFAULT 'http',
+    ADD COLUMN description TEXT NOT NULL DEFAULT '',
+    ADD COLUMN category TEXT,
+    ADD COLUMN author TEXT;
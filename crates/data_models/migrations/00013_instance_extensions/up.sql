@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN extensions INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+CREATE TABLE scheduler_leases (
+    name       TEXT PRIMARY KEY,
+    holder     TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
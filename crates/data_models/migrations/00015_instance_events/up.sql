@@ -0,0 +1,11 @@
+CREATE TABLE instance_events (
+    id          BIGSERIAL PRIMARY KEY,
+    instance_id INTEGER NOT NULL REFERENCES instances(id) ON DELETE CASCADE,
+    kind        TEXT NOT NULL,
+    message     TEXT NOT NULL,
+    -- Set for events that must be emitted at most once, e.g. one expiry
+    -- warning per instance, threshold and expiry time.
+    dedupe_key  TEXT UNIQUE,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX instance_events_instance_idx ON instance_events (instance_id);
@@ -0,0 +1 @@
+ALTER TABLE instances DROP COLUMN stop_reason;
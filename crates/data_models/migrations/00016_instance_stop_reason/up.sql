@@ -0,0 +1,3 @@
+-- 'user', 'admin', 'expired' or 'idle'; NULL for running instances and
+-- rows stopped before reasons were recorded.
+ALTER TABLE instances ADD COLUMN stop_reason TEXT;
@@ -0,0 +1,9 @@
+-- Deploys waiting for capacity, served in id order. One place per user
+-- keeps the queue fair.
+CREATE TABLE deploy_queue (
+    id         BIGSERIAL PRIMARY KEY,
+    user_id    INTEGER NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    task_name  TEXT NOT NULL,
+    team_id    INTEGER REFERENCES teams(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+-- The Docker node running the instance's container; rows from before
+-- multi-host support ran on the local daemon.
+ALTER TABLE instances ADD COLUMN node TEXT NOT NULL DEFAULT 'local';
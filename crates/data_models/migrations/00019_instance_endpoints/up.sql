@@ -0,0 +1,3 @@
+-- Endpoints of the exposed services of multi-container tasks, besides
+-- the main `endpoint`.
+ALTER TABLE instances ADD COLUMN endpoints TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+DELETE FROM deploy_queue WHERE failure IS NOT NULL;
+ALTER TABLE deploy_queue DROP COLUMN failed_at;
+ALTER TABLE deploy_queue DROP COLUMN failure;
@@ -0,0 +1,4 @@
+-- A queued deploy that cannot start keeps its row, with the reason, so its
+-- owner finds out on GET /queue. Failed rows no longer hold a place.
+ALTER TABLE deploy_queue ADD COLUMN failure TEXT;
+ALTER TABLE deploy_queue ADD COLUMN failed_at TIMESTAMPTZ;
@@ -1,22 +1,34 @@
 use chrono::{DateTime, Utc};
-use common::{TaskInstance, InstanceStatus, ServiceError, User};
-use config_manager::get_config;
+use common::{ApiKey, ApiScope, AuditEntry, InstanceEvent, QueueEntry, SharedFlagSubmission, StopReason, TaskInstance, TaskUsage, InstanceStatus, Role, ServiceError, Team, User};
+use config_manager::{TaskConfig, get_config};
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::sql_types::{Double, Text};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 type PgPool = Pool<ConnectionManager<PgConnection>>;
 
+#[derive(Clone)]
 pub struct Db {
     pool: PgPool,
 }
 
+/// Criteria for [`Db::list_instances_filtered`]; unset fields match everything.
+#[derive(Debug, Default)]
+pub struct InstanceFilter {
+    pub task_name: Option<String>,
+    pub user_id: Option<i32>,
+    pub status: Option<InstanceStatus>,
+    /// Only instances created before this moment.
+    pub created_before: Option<DateTime<Utc>>,
+}
+
 impl Db {
     pub fn new() -> Result<Self, ServiceError> {
         let cfg = get_config();
-        let manager = ConnectionManager::<PgConnection>::new(&cfg.database.url);
+        let manager = ConnectionManager::<PgConnection>::new(cfg.database.url.expose());
         let pool = Pool::builder().build(manager)?;
         let mut conn = pool.get()?;
         conn.run_pending_migrations(MIGRATIONS)
@@ -49,6 +61,10 @@ impl Db {
             status: inst.status.as_str().to_string(),
             endpoint: inst.endpoint.clone(),
             user_id: inst.user_id,
+            team_id: inst.team_id,
+            flag: inst.flag.clone(),
+            node: inst.node.clone(),
+            endpoints: inst.endpoints.clone(),
         };
 
         let saved_row: RowInstance = diesel::insert_into(instances::table)
@@ -68,6 +84,60 @@ impl Db {
             .execute(&mut c)?;
         Ok(())
     }
+    /// Point an instance at the container that replaced its old one.
+    pub fn replace_instance_container(&self, id_: i32, container_id_: &str) -> Result<(), ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut c = self.get_conn()?;
+        diesel::update(instances.filter(id.eq(id_)))
+            .set(container_id.eq(container_id_))
+            .execute(&mut c)?;
+        Ok(())
+    }
+    /// Mark an instance stopped now, recording why.
+    pub fn stop_instance(&self, id_: i32, reason: StopReason) -> Result<(), ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut c = self.get_conn()?;
+        diesel::update(instances.filter(id.eq(id_)))
+            .set((
+                status.eq(InstanceStatus::Stopped.as_str()),
+                expires_at.eq(Utc::now()),
+                stop_reason.eq(reason.as_str()),
+            ))
+            .execute(&mut c)?;
+        Ok(())
+    }
+    /// Set a new expiry for a player extension and count it, unless the
+    /// instance stopped or was extended since `seen` was read.
+    pub fn extend_instance(&self, id_: i32, seen: i32, expires_at_: DateTime<Utc>)
+                           -> Result<bool, ServiceError>
+    {
+        use crate::schema::instances::dsl::*;
+        let mut c = self.get_conn()?;
+        let n = diesel::update(
+            instances
+                .filter(id.eq(id_))
+                .filter(extensions.eq(seen))
+                .filter(status.eq(InstanceStatus::Running.as_str())),
+        )
+            .set((expires_at.eq(expires_at_), extensions.eq(extensions + 1)))
+            .execute(&mut c)?;
+        Ok(n == 1)
+    }
+    /// Mark a running instance failed, unless it stopped or its container
+    /// was replaced since `seen_container` was read.
+    pub fn fail_lost_instance(&self, id_: i32, seen_container: &str) -> Result<bool, ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut c = self.get_conn()?;
+        let n = diesel::update(
+            instances
+                .filter(id.eq(id_))
+                .filter(container_id.eq(seen_container))
+                .filter(status.eq(InstanceStatus::Running.as_str())),
+        )
+            .set(status.eq(InstanceStatus::Failed.as_str()))
+            .execute(&mut c)?;
+        Ok(n == 1)
+    }
     pub fn find_instance_by_id(&self, id_: i32) -> Result<Option<TaskInstance>, ServiceError> {
         use crate::schema::instances::dsl::*;
         let mut conn = self.get_conn()?;
@@ -78,6 +148,18 @@ impl Db {
         Ok(row.map(|r| r.into()))
     }
 
+    /// Most recent instance carrying `flag_`, whatever its status.
+    pub fn find_instance_by_flag(&self, flag_: &str) -> Result<Option<TaskInstance>, ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut conn = self.get_conn()?;
+        let row = instances
+            .filter(flag.eq(flag_))
+            .order(id.desc())
+            .first::<RowInstance>(&mut conn)
+            .optional()?;
+        Ok(row.map(|r| r.into()))
+    }
+
     pub fn count_running_instances_for_user(&self, uid: i32) -> Result<i64, ServiceError> {
         use crate::schema::instances::dsl::*;
         let mut c = self.get_conn()?;
@@ -105,6 +187,10 @@ impl Db {
             status: inst.status.as_str().to_string(),
             endpoint: inst.endpoint.clone(),
             user_id: uid,
+            team_id: inst.team_id,
+            flag: inst.flag.clone(),
+            node: inst.node.clone(),
+            endpoints: inst.endpoints.clone(),
         };
 
         let saved_row: RowInstance = diesel::insert_into(instances::table)
@@ -120,51 +206,44 @@ impl Db {
 
         if let Some(row) = users::dsl::users
             .filter(users::dsl::username.eq(name))
+            .select(USER_COLUMNS)
             .first::<RowUser>(&mut conn)
             .optional()?
         {
-            return Ok(User {
-                id: row.id,
-                username: row.username,
-                created_at: row.created_at,
-            });
+            return Ok(row.into());
         }
 
         let new = NewUser { username: name };
         let row = diesel::insert_into(users::table)
             .values(&new)
+            .returning(USER_COLUMNS)
             .get_result::<RowUser>(&mut conn)?;
-        Ok(User {
-            id: row.id,
-            username: row.username,
-            created_at: row.created_at,
-        })
+        Ok(row.into())
     }
-    pub fn find_valid_session_for_user(&self, uid: i32) -> Result<Option<String>, ServiceError> {
-        use crate::schema::sessions::dsl::*;
+
+    pub fn set_user_role(&self, name: &str, new_role: Role) -> Result<User, ServiceError> {
+        use crate::schema::users::dsl::*;
+        let user = self.find_or_create_user(name)?;
         let mut conn = self.get_conn()?;
-        let now = Utc::now();
-        let token_opt = sessions
-            .filter(user_id.eq(uid))
-            .filter(expires_at.gt(now))
-            .select(id)
-            .first::<String>(&mut conn)
-            .optional()?;
-        Ok(token_opt)
+        let row = diesel::update(users.filter(id.eq(user.id)))
+            .set(role.eq(new_role.as_str()))
+            .returning(USER_COLUMNS)
+            .get_result::<RowUser>(&mut conn)?;
+        Ok(row.into())
     }
-
     /// Fetch the full session row for a given token, including its expiry.
     pub fn get_session(&self, token_str: &str) -> Result<Option<common::UserSession>, ServiceError> {
         use crate::schema::sessions::dsl::*;
         let mut conn = self.get_conn()?;
+        let digest = hash_session_token(token_str);
         // Query the raw row
         let opt_row = sessions
-            .filter(id.eq(token_str))
+            .filter(token_hash.eq(&digest))
             .first::<RowSession>(&mut conn)
             .optional()?;
         // Map RowSession → common::UserSession
         Ok(opt_row.map(|r| common::UserSession {
-            session_id: r.id,
+            token_hash: r.token_hash,
             user_id: r.user_id,
             created_at: r.created_at,
             expires_at: r.expires_at,
@@ -188,8 +267,9 @@ impl Db {
     ) -> Result<(), ServiceError> {
         use crate::schema::sessions;
         let mut conn = self.get_conn()?;
+        let digest = hash_session_token(token_str);
         let new = NewSession {
-            id: token_str,
+            token_hash: &digest,
             user_id: uid,
             expires_at: expires_at_val,
         };
@@ -205,21 +285,19 @@ impl Db {
 
         let mut conn = self.get_conn()?;
         let now = Utc::now();
+        let digest = hash_session_token(token);
 
         let opt_row = sessions::table
             .inner_join(users::table)
-            .filter(sessions::id.eq(token))
+            .filter(sessions::token_hash.eq(&digest))
             .filter(sessions::expires_at.gt(now))
-            .select((users::id, users::username, users::created_at))
+            .filter(users::banned.eq(false))
+            .select(USER_COLUMNS)
             .first::<RowUser>(&mut conn)
             .optional()?;
 
         // Map RowUser → common::User
-        Ok(opt_row.map(|r| User {
-            id: r.id,
-            username: r.username,
-            created_at: r.created_at,
-        }))
+        Ok(opt_row.map(Into::into))
     }
     pub fn list_expired_instances(
         &self,
@@ -245,29 +323,685 @@ impl Db {
             .execute(&mut conn)?;
         Ok(())
     }
-    pub fn ensure_task(&self, name: &str, dockerfile_path: &str) -> Result<(), ServiceError> {
-        use crate::schema::tasks;
+    /// Insert or refresh a task row from its resolved configuration. A task
+    /// whose `author` names an existing user becomes owned by that user.
+    pub fn ensure_task(
+        &self,
+        task_name: &str,
+        dockerfile: &str,
+        task_cfg: &TaskConfig,
+    ) -> Result<(), ServiceError> {
+        use crate::schema::tasks::dsl::*;
+        let owner = match &task_cfg.author {
+            Some(a) => self.find_user(a)?.map(|u| u.id),
+            None => None,
+        };
+        let mut conn = self.get_conn()?;
+        let fields = (
+            dockerfile_path.eq(dockerfile),
+            protocol.eq(&task_cfg.protocol),
+            description.eq(&task_cfg.description),
+            category.eq(&task_cfg.category),
+            author.eq(&task_cfg.author),
+        );
+        diesel::insert_into(tasks)
+            .values((name.eq(task_name), fields))
+            .on_conflict(name)
+            .do_update()
+            .set(fields)
+            .execute(&mut conn)?;
+        if let Some(uid) = owner {
+            diesel::update(tasks.filter(name.eq(task_name)))
+                .set(owner_id.eq(Some(uid)))
+                .execute(&mut conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn task_exists(&self, task: &str) -> Result<bool, ServiceError> {
+        use crate::schema::tasks::dsl::*;
+        let mut conn = self.get_conn()?;
+        let found = diesel::select(diesel::dsl::exists(tasks.filter(name.eq(task))))
+            .get_result::<bool>(&mut conn)?;
+        Ok(found)
+    }
+
+    /// Owner of a task, if the task exists and has one assigned.
+    pub fn task_owner(&self, task: &str) -> Result<Option<i32>, ServiceError> {
+        use crate::schema::tasks::dsl::*;
         let mut conn = self.get_conn()?;
-        diesel::insert_into(tasks::table)
+        let owner = tasks
+            .filter(name.eq(task))
+            .select(owner_id)
+            .first::<Option<i32>>(&mut conn)
+            .optional()?;
+        Ok(owner.flatten())
+    }
+
+    pub fn set_task_owner(&self, task: &str, uid: i32) -> Result<bool, ServiceError> {
+        use crate::schema::tasks::dsl::*;
+        let mut conn = self.get_conn()?;
+        let updated = diesel::update(tasks.filter(name.eq(task)))
+            .set(owner_id.eq(Some(uid)))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    pub fn find_user(&self, name: &str) -> Result<Option<User>, ServiceError> {
+        use crate::schema::users::dsl::*;
+        let mut conn = self.get_conn()?;
+        let row = users
+            .filter(username.eq(name))
+            .select(USER_COLUMNS)
+            .first::<RowUser>(&mut conn)
+            .optional()?;
+        Ok(row.map(Into::into))
+    }
+
+    /// Ban or unban a user. Banned users' sessions stop validating immediately.
+    pub fn set_user_banned(&self, uid: i32, banned_: bool) -> Result<(), ServiceError> {
+        use crate::schema::users::dsl::*;
+        let mut conn = self.get_conn()?;
+        diesel::update(users.filter(id.eq(uid)))
+            .set(banned.eq(banned_))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    pub fn list_instances_filtered(
+        &self,
+        filter: &InstanceFilter,
+    ) -> Result<Vec<TaskInstance>, ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut conn = self.get_conn()?;
+        let mut query = instances.into_boxed();
+        if let Some(t) = &filter.task_name {
+            query = query.filter(task_name.eq(t));
+        }
+        if let Some(uid) = filter.user_id {
+            query = query.filter(user_id.eq(uid));
+        }
+        if let Some(st) = filter.status {
+            query = query.filter(status.eq(st.as_str()));
+        }
+        if let Some(before) = filter.created_before {
+            query = query.filter(created_at.lt(before));
+        }
+        let rows = query.order(id.desc()).load::<RowInstance>(&mut conn)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    pub fn task_usage(&self) -> Result<Vec<TaskUsage>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let rows = diesel::sql_query(
+            "SELECT task_name,
+                    COUNT(*) AS total_instances,
+                    COUNT(*) FILTER (WHERE status = 'Running') AS running_instances,
+                    COUNT(DISTINCT user_id) AS distinct_users
+             FROM instances
+             GROUP BY task_name
+             ORDER BY task_name",
+        )
+        .load::<RowTaskUsage>(&mut conn)?;
+        Ok(rows
+            .into_iter()
+            .map(|r| TaskUsage {
+                task_name: r.task_name,
+                total_instances: r.total_instances,
+                running_instances: r.running_instances,
+                distinct_users: r.distinct_users,
+            })
+            .collect())
+    }
+
+    pub fn record_audit(
+        &self,
+        actor: i32,
+        action_: &str,
+        target_: &str,
+        detail_: &str,
+    ) -> Result<(), ServiceError> {
+        use crate::schema::audit_log::dsl::*;
+        let mut conn = self.get_conn()?;
+        diesel::insert_into(audit_log)
             .values((
-                tasks::dsl::name.eq(name),
-                tasks::dsl::dockerfile_path.eq(dockerfile_path),
+                actor_id.eq(actor),
+                action.eq(action_),
+                target.eq(target_),
+                detail.eq(detail_),
             ))
-            .on_conflict(tasks::dsl::name)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    pub fn list_audit(&self, limit: i64) -> Result<Vec<AuditEntry>, ServiceError> {
+        use crate::schema::audit_log::dsl::*;
+        let mut conn = self.get_conn()?;
+        let rows = audit_log
+            .order(id.desc())
+            .limit(limit)
+            .load::<RowAudit>(&mut conn)?;
+        Ok(rows
+            .into_iter()
+            .map(|r| AuditEntry {
+                id: r.id,
+                actor_id: r.actor_id,
+                action: r.action,
+                target: r.target,
+                detail: r.detail,
+                created_at: r.created_at,
+            })
+            .collect())
+    }
+    pub fn count_running_instances_for_team(&self, tid: i32) -> Result<i64, ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut c = self.get_conn()?;
+        let cnt: i64 = instances
+            .filter(team_id.eq(tid))
+            .filter(status.eq("Running"))
+            .count()
+            .get_result(&mut c)?;
+        Ok(cnt)
+    }
+
+    /// Running instances the user owns plus those owned by their team.
+    pub fn list_instances_visible_to(&self, user: &User) -> Result<Vec<TaskInstance>, ServiceError> {
+        use crate::schema::instances::dsl::*;
+        let mut conn = self.get_conn()?;
+        let mut query = instances
+            .filter(status.eq("Running"))
+            .into_boxed();
+        query = match user.team_id {
+            Some(tid) => query.filter(user_id.eq(user.id).or(team_id.eq(tid))),
+            None => query.filter(user_id.eq(user.id)),
+        };
+        let rows = query.load::<RowInstance>(&mut conn)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Create a team and make `creator` its first member.
+    pub fn create_team(&self, team_name: &str, code: &str, creator: i32) -> Result<Team, ServiceError> {
+        let mut conn = self.get_conn()?;
+        conn.transaction(|conn| {
+            let row = diesel::insert_into(teams::table)
+                .values((teams::name.eq(team_name), teams::join_code.eq(code)))
+                .returning(TEAM_COLUMNS)
+                .get_result::<RowTeam>(conn)?;
+            diesel::update(users::table.filter(users::id.eq(creator)))
+                .set(users::team_id.eq(Some(row.id)))
+                .execute(conn)?;
+            Ok(row.into())
+        })
+    }
+
+    pub fn find_team_by_id(&self, tid: i32) -> Result<Option<Team>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let row = teams::table
+            .filter(teams::id.eq(tid))
+            .select(TEAM_COLUMNS)
+            .first::<RowTeam>(&mut conn)
+            .optional()?;
+        Ok(row.map(Into::into))
+    }
+
+    pub fn team_join_code(&self, tid: i32) -> Result<Option<String>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let code = teams::table
+            .filter(teams::id.eq(tid))
+            .select(teams::join_code)
+            .first::<String>(&mut conn)
+            .optional()?;
+        Ok(code)
+    }
+
+    /// Join the team named `team_name` if `code` matches its join code.
+    pub fn join_team(&self, uid: i32, team_name: &str, code: &str) -> Result<Option<Team>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let row = teams::table
+            .filter(teams::name.eq(team_name))
+            .filter(teams::join_code.eq(code))
+            .select(TEAM_COLUMNS)
+            .first::<RowTeam>(&mut conn)
+            .optional()?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        diesel::update(users::table.filter(users::id.eq(uid)))
+            .set(users::team_id.eq(Some(row.id)))
+            .execute(&mut conn)?;
+        Ok(Some(row.into()))
+    }
+
+    pub fn leave_team(&self, uid: i32) -> Result<(), ServiceError> {
+        let mut conn = self.get_conn()?;
+        diesel::update(users::table.filter(users::id.eq(uid)))
+            .set(users::team_id.eq(None::<i32>))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    pub fn team_members(&self, tid: i32) -> Result<Vec<User>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let rows = users::table
+            .filter(users::team_id.eq(tid))
+            .select(USER_COLUMNS)
+            .load::<RowUser>(&mut conn)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+    /// Store a new API key. Like session tokens, only its keyed hash is kept.
+    pub fn create_api_key(
+        &self,
+        uid: i32,
+        key_name: &str,
+        key: &str,
+        key_scopes: &[ApiScope],
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<ApiKey, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let digest = hash_session_token(key);
+        let row = diesel::insert_into(api_keys::table)
+            .values((
+                api_keys::user_id.eq(uid),
+                api_keys::name.eq(key_name),
+                api_keys::key_hash.eq(&digest),
+                api_keys::scopes.eq(ApiScope::join(key_scopes)),
+                api_keys::expires_at.eq(expires),
+            ))
+            .returning(API_KEY_COLUMNS)
+            .get_result::<RowApiKey>(&mut conn)?;
+        Ok(row.into())
+    }
+
+    /// Resolve an API key to its owner and scopes, if it is live.
+    pub fn validate_api_key(&self, key: &str) -> Result<Option<(User, Vec<ApiScope>)>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let now = Utc::now();
+        let digest = hash_session_token(key);
+        let row = api_keys::table
+            .inner_join(users::table)
+            .filter(api_keys::key_hash.eq(&digest))
+            .filter(api_keys::revoked_at.is_null())
+            .filter(api_keys::expires_at.is_null().or(api_keys::expires_at.gt(now)))
+            .filter(users::banned.eq(false))
+            .select((USER_COLUMNS, api_keys::scopes))
+            .first::<(RowUser, String)>(&mut conn)
+            .optional()?;
+        Ok(row.map(|(u, sc)| (u.into(), ApiScope::parse_list(&sc))))
+    }
+
+    pub fn list_api_keys(&self, uid: i32) -> Result<Vec<ApiKey>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let rows = api_keys::table
+            .filter(api_keys::user_id.eq(uid))
+            .order(api_keys::id)
+            .select(API_KEY_COLUMNS)
+            .load::<RowApiKey>(&mut conn)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Revoke one of the user's keys. Returns false if no such live key exists.
+    pub fn revoke_api_key(&self, uid: i32, key_id: i32) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let updated = diesel::update(
+            api_keys::table
+                .filter(api_keys::id.eq(key_id))
+                .filter(api_keys::user_id.eq(uid))
+                .filter(api_keys::revoked_at.is_null()),
+        )
+        .set(api_keys::revoked_at.eq(Some(Utc::now())))
+        .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+    pub fn count_recent_wrong_submissions(
+        &self,
+        uid: i32,
+        task: &str,
+        since: DateTime<Utc>,
+    ) -> Result<i64, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let cnt = submissions::table
+            .filter(submissions::user_id.eq(uid))
+            .filter(submissions::task_name.eq(task))
+            .filter(submissions::correct.eq(false))
+            .filter(submissions::created_at.gt(since))
+            .count()
+            .get_result(&mut conn)?;
+        Ok(cnt)
+    }
+
+    pub fn record_submission(&self, sub: &NewSubmission) -> Result<(), ServiceError> {
+        let mut conn = self.get_conn()?;
+        diesel::insert_into(submissions::table)
+            .values((
+                submissions::user_id.eq(sub.user_id),
+                submissions::team_id.eq(sub.team_id),
+                submissions::task_name.eq(sub.task_name),
+                submissions::submitted.eq(sub.submitted),
+                submissions::correct.eq(sub.correct),
+                submissions::shared_from_instance.eq(sub.shared_from_instance),
+            ))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Record a solve. Returns false if the user had already solved the task.
+    pub fn record_solve(&self, uid: i32, tid: Option<i32>, task: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let inserted = diesel::insert_into(solves::table)
+            .values((
+                solves::user_id.eq(uid),
+                solves::team_id.eq(tid),
+                solves::task_name.eq(task),
+            ))
+            .on_conflict((solves::user_id, solves::task_name))
             .do_nothing()
             .execute(&mut conn)?;
+        Ok(inserted > 0)
+    }
+
+    pub fn list_shared_flag_submissions(&self, limit: i64) -> Result<Vec<SharedFlagSubmission>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let rows = submissions::table
+            .inner_join(instances::table)
+            .order(submissions::id.desc())
+            .limit(limit)
+            .select((
+                submissions::id,
+                submissions::user_id,
+                submissions::task_name,
+                instances::id,
+                instances::user_id,
+                submissions::created_at,
+            ))
+            .load::<(i32, i32, String, i32, i32, DateTime<Utc>)>(&mut conn)?;
+        Ok(rows
+            .into_iter()
+            .map(|(sid, uid, task, iid, owner, at)| SharedFlagSubmission {
+                submission_id: sid,
+                user_id: uid,
+                task_name: task,
+                instance_id: iid,
+                instance_owner_id: owner,
+                created_at: at,
+            })
+            .collect())
+    }
+
+    /// Record an event for `inst`. With a `dedupe` key the event is stored
+    /// only once; `None` means it already existed.
+    pub fn record_instance_event(
+        &self,
+        inst: &TaskInstance,
+        kind: &str,
+        message: &str,
+        dedupe: Option<&str>,
+    ) -> Result<Option<InstanceEvent>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let row = diesel::insert_into(instance_events::table)
+            .values((
+                instance_events::instance_id.eq(inst.id),
+                instance_events::kind.eq(kind),
+                instance_events::message.eq(message),
+                instance_events::dedupe_key.eq(dedupe),
+            ))
+            .on_conflict(instance_events::dedupe_key)
+            .do_nothing()
+            .returning((instance_events::id, instance_events::created_at))
+            .get_result::<(i64, DateTime<Utc>)>(&mut conn)
+            .optional()?;
+        Ok(row.map(|(event_id, at)| InstanceEvent {
+            id: event_id,
+            instance_id: inst.id,
+            task_name: inst.task_name.clone(),
+            user_id: inst.user_id,
+            team_id: inst.team_id,
+            kind: kind.to_string(),
+            message: message.to_string(),
+            created_at: at,
+        }))
+    }
+
+    /// Events after `after` on instances `user` can see: their own and
+    /// their team's. Oldest first.
+    pub fn list_instance_events_for(
+        &self,
+        user: &User,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<InstanceEvent>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let mut query = instance_events::table
+            .inner_join(instances::table)
+            .filter(instance_events::id.gt(after))
+            .into_boxed();
+        query = match user.team_id {
+            Some(tid) => query.filter(instances::user_id.eq(user.id).or(instances::team_id.eq(tid))),
+            None => query.filter(instances::user_id.eq(user.id)),
+        };
+        let rows = query
+            .order(instance_events::id.asc())
+            .limit(limit)
+            .select((
+                instance_events::id,
+                instance_events::instance_id,
+                instances::task_name,
+                instances::user_id,
+                instances::team_id,
+                instance_events::kind,
+                instance_events::message,
+                instance_events::created_at,
+            ))
+            .load::<(i64, i32, String, i32, Option<i32>, String, String, DateTime<Utc>)>(&mut conn)?;
+        Ok(rows
+            .into_iter()
+            .map(|(eid, iid, task, uid, tid, k, msg, at)| InstanceEvent {
+                id: eid,
+                instance_id: iid,
+                task_name: task,
+                user_id: uid,
+                team_id: tid,
+                kind: k,
+                message: msg,
+                created_at: at,
+            })
+            .collect())
+    }
+
+    /// Id of the newest event, or 0; the cursor for a stream that only
+    /// wants events from now on.
+    pub fn latest_instance_event_id(&self) -> Result<i64, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let id = instance_events::table
+            .select(diesel::dsl::max(instance_events::id))
+            .first::<Option<i64>>(&mut conn)?;
+        Ok(id.unwrap_or(0))
+    }
+
+    /// Queue a deploy for `user_id`, or return their existing place. A
+    /// failed deploy of theirs is replaced.
+    pub fn enqueue_deploy(
+        &self,
+        user_id: i32,
+        task_name: &str,
+        team_id: Option<i32>,
+    ) -> Result<QueueEntry, ServiceError> {
+        let mut conn = self.get_conn()?;
+        diesel::delete(
+            deploy_queue::table
+                .filter(deploy_queue::user_id.eq(user_id))
+                .filter(deploy_queue::failure.is_not_null()),
+        )
+        .execute(&mut conn)?;
+        diesel::insert_into(deploy_queue::table)
+            .values((
+                deploy_queue::user_id.eq(user_id),
+                deploy_queue::task_name.eq(task_name),
+                deploy_queue::team_id.eq(team_id),
+            ))
+            .on_conflict(deploy_queue::user_id)
+            .do_nothing()
+            .execute(&mut conn)?;
+        let row = deploy_queue::table
+            .filter(deploy_queue::user_id.eq(user_id))
+            .first::<RowQueueEntry>(&mut conn)?;
+        Ok(row.into())
+    }
+
+    /// The user's queued deploy and its 1-based position, which is 0 once
+    /// the deploy has failed.
+    pub fn queue_position(&self, user_id: i32) -> Result<Option<(QueueEntry, i64)>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let Some(row) = deploy_queue::table
+            .filter(deploy_queue::user_id.eq(user_id))
+            .first::<RowQueueEntry>(&mut conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        if row.failure.is_some() {
+            return Ok(Some((row.into(), 0)));
+        }
+        let ahead: i64 = deploy_queue::table
+            .filter(deploy_queue::id.lt(row.id))
+            .filter(deploy_queue::failure.is_null())
+            .count()
+            .get_result(&mut conn)?;
+        Ok(Some((row.into(), ahead + 1)))
+    }
+
+    /// Every waiting deploy, first come first.
+    pub fn list_deploy_queue(&self) -> Result<Vec<QueueEntry>, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let rows = deploy_queue::table
+            .filter(deploy_queue::failure.is_null())
+            .order(deploy_queue::id.asc())
+            .load::<RowQueueEntry>(&mut conn)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    pub fn deploy_queue_len(&self) -> Result<i64, ServiceError> {
+        let mut conn = self.get_conn()?;
+        Ok(deploy_queue::table
+            .filter(deploy_queue::failure.is_null())
+            .count()
+            .get_result(&mut conn)?)
+    }
+
+    /// Take a waiting deploy off the queue; false if it is no longer there,
+    /// e.g. another worker claimed it or its owner left.
+    pub fn remove_queue_entry(&self, entry_id: i64) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let n = diesel::delete(
+            deploy_queue::table
+                .filter(deploy_queue::id.eq(entry_id))
+                .filter(deploy_queue::failure.is_null()),
+        )
+        .execute(&mut conn)?;
+        Ok(n > 0)
+    }
+
+    /// Record that a queued deploy will not start, for its owner to see. An
+    /// entry already taken off the queue is put back as failed, unless the
+    /// owner has queued again since.
+    pub fn fail_queued_deploy(&self, entry: &QueueEntry, reason: &str) -> Result<(), ServiceError> {
+        let mut conn = self.get_conn()?;
+        let now = Utc::now();
+        let n = diesel::update(
+            deploy_queue::table
+                .filter(deploy_queue::id.eq(entry.id))
+                .filter(deploy_queue::failure.is_null()),
+        )
+        .set((deploy_queue::failure.eq(reason), deploy_queue::failed_at.eq(now)))
+        .execute(&mut conn)?;
+        if n == 0 {
+            diesel::insert_into(deploy_queue::table)
+                .values((
+                    deploy_queue::user_id.eq(entry.user_id),
+                    deploy_queue::task_name.eq(&entry.task_name),
+                    deploy_queue::team_id.eq(entry.team_id),
+                    deploy_queue::created_at.eq(entry.created_at),
+                    deploy_queue::failure.eq(reason),
+                    deploy_queue::failed_at.eq(now),
+                ))
+                .on_conflict(deploy_queue::user_id)
+                .do_nothing()
+                .execute(&mut conn)?;
+        }
         Ok(())
     }
+
+    /// Drop the user's queued deploy, if any.
+    pub fn leave_deploy_queue(&self, user_id: i32) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let n = diesel::delete(deploy_queue::table.filter(deploy_queue::user_id.eq(user_id)))
+            .execute(&mut conn)?;
+        Ok(n > 0)
+    }
+
+    /// Fail deploys still waiting since before `before` with `reason`, and
+    /// forget failures older than that. Returns how many deploys failed.
+    pub fn expire_deploy_queue(&self, before: DateTime<Utc>, reason: &str) -> Result<usize, ServiceError> {
+        let mut conn = self.get_conn()?;
+        diesel::delete(deploy_queue::table.filter(deploy_queue::failed_at.lt(before)))
+            .execute(&mut conn)?;
+        Ok(diesel::update(
+            deploy_queue::table
+                .filter(deploy_queue::created_at.lt(before))
+                .filter(deploy_queue::failure.is_null()),
+        )
+        .set((deploy_queue::failure.eq(reason), deploy_queue::failed_at.eq(Utc::now())))
+        .execute(&mut conn)?)
+    }
+
+    /// Take or renew the lease `name` for `holder` until `ttl_secs` from now
+    /// (database clock). Succeeds if the lease is free, expired, or already
+    /// held by `holder`.
+    pub fn try_acquire_lease(&self, name: &str, holder: &str, ttl_secs: u64) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let n = diesel::sql_query(
+            "INSERT INTO scheduler_leases (name, holder, expires_at)
+             VALUES ($1, $2, now() + make_interval(secs => $3))
+             ON CONFLICT (name) DO UPDATE
+                 SET holder = EXCLUDED.holder, expires_at = EXCLUDED.expires_at
+                 WHERE scheduler_leases.holder = EXCLUDED.holder
+                    OR scheduler_leases.expires_at < now()",
+        )
+        .bind::<Text, _>(name)
+        .bind::<Text, _>(holder)
+        .bind::<Double, _>(ttl_secs as f64)
+        .execute(&mut conn)?;
+        Ok(n == 1)
+    }
+
+    /// Give up the lease `name` if `holder` has it, so another replica can
+    /// take over immediately.
+    pub fn release_lease(&self, name: &str, holder: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.get_conn()?;
+        let n = diesel::sql_query("DELETE FROM scheduler_leases WHERE name = $1 AND holder = $2")
+            .bind::<Text, _>(name)
+            .bind::<Text, _>(holder)
+            .execute(&mut conn)?;
+        Ok(n == 1)
+    }
 }
 
 
 
+fn hash_session_token(token: &str) -> String {
+    common::hash_token(get_config().sessions.token_secret.expose(), token)
+}
+
 pub mod schema {
     diesel::table! {
         tasks (name) {
             name -> Text,
             dockerfile_path -> Text,
             created_at -> Timestamptz,
+            owner_id -> Nullable<Int4>,
+            protocol -> Text,
+            description -> Text,
+            category -> Nullable<Text>,
+            author -> Nullable<Text>,
         }
     }
 
@@ -276,12 +1010,26 @@ pub mod schema {
             id -> Int4,
             username -> Text,
             created_at -> Timestamptz,
+            role -> Text,
+            banned -> Bool,
+            team_id -> Nullable<Int4>,
+        }
+    }
+
+    diesel::table! {
+        audit_log (id) {
+            id -> Int4,
+            actor_id -> Int4,
+            action -> Text,
+            target -> Text,
+            detail -> Text,
+            created_at -> Timestamptz,
         }
     }
 
     diesel::table! {
-        sessions (id) {
-            id -> Text,
+        sessions (token_hash) {
+            token_hash -> Text,
             user_id -> Int4,
             created_at -> Timestamptz,
             expires_at -> Timestamptz,
@@ -298,24 +1046,152 @@ pub mod schema {
             status -> Text,
             endpoint -> Text,
             user_id -> Int4,
+            team_id -> Nullable<Int4>,
+            flag -> Nullable<Text>,
+            extensions -> Int4,
+            stop_reason -> Nullable<Text>,
+            node -> Text,
+            endpoints -> Array<Text>,
+        }
+    }
+
+    diesel::table! {
+        api_keys (id) {
+            id -> Int4,
+            user_id -> Int4,
+            name -> Text,
+            key_hash -> Text,
+            scopes -> Text,
+            created_at -> Timestamptz,
+            expires_at -> Nullable<Timestamptz>,
+            revoked_at -> Nullable<Timestamptz>,
+        }
+    }
+
+    diesel::table! {
+        submissions (id) {
+            id -> Int4,
+            user_id -> Int4,
+            team_id -> Nullable<Int4>,
+            task_name -> Text,
+            submitted -> Text,
+            correct -> Bool,
+            shared_from_instance -> Nullable<Int4>,
+            created_at -> Timestamptz,
+        }
+    }
+
+    diesel::table! {
+        solves (user_id, task_name) {
+            user_id -> Int4,
+            task_name -> Text,
+            team_id -> Nullable<Int4>,
+            solved_at -> Timestamptz,
+        }
+    }
+
+    diesel::table! {
+        instance_events (id) {
+            id -> Int8,
+            instance_id -> Int4,
+            kind -> Text,
+            message -> Text,
+            dedupe_key -> Nullable<Text>,
+            created_at -> Timestamptz,
+        }
+    }
+
+    diesel::table! {
+        deploy_queue (id) {
+            id -> Int8,
+            user_id -> Int4,
+            task_name -> Text,
+            team_id -> Nullable<Int4>,
+            created_at -> Timestamptz,
+            failure -> Nullable<Text>,
+            failed_at -> Nullable<Timestamptz>,
+        }
+    }
+
+    diesel::table! {
+        teams (id) {
+            id -> Int4,
+            name -> Text,
+            join_code -> Text,
+            created_at -> Timestamptz,
         }
     }
 }
 joinable!(sessions -> users (user_id));
+joinable!(api_keys -> users (user_id));
+joinable!(submissions -> instances (shared_from_instance));
+joinable!(instance_events -> instances (instance_id));
 
 // Allow both tables in the same query
 allow_tables_to_appear_in_same_query!(
+    api_keys,
+    instance_events,
+    instances,
     sessions,
+    submissions,
     users,
 );
 
-use crate::schema::{sessions, users, instances};
+use crate::schema::{api_keys, deploy_queue, instance_events, sessions, solves, submissions, teams, users, instances};
+
+/// One `/submit` attempt, as recorded by [`Db::record_submission`].
+pub struct NewSubmission<'a> {
+    pub user_id: i32,
+    pub team_id: Option<i32>,
+    pub task_name: &'a str,
+    pub submitted: &'a str,
+    pub correct: bool,
+    pub shared_from_instance: Option<i32>,
+}
+
+type UserColumns = (users::id, users::username, users::created_at, users::role, users::team_id);
+const USER_COLUMNS: UserColumns =
+    (users::id, users::username, users::created_at, users::role, users::team_id);
 
 #[derive(Queryable)]
 struct RowUser {
     id: i32,
     username: String,
     created_at: DateTime<Utc>,
+    role: String,
+    team_id: Option<i32>,
+}
+
+impl From<RowUser> for User {
+    fn from(r: RowUser) -> Self {
+        User {
+            id: r.id,
+            username: r.username,
+            created_at: r.created_at,
+            role: Role::parse(&r.role).unwrap_or(Role::Player),
+            team_id: r.team_id,
+        }
+    }
+}
+
+#[derive(Queryable)]
+struct RowTeam {
+    id: i32,
+    name: String,
+    created_at: DateTime<Utc>,
+}
+
+const TEAM_COLUMNS: (teams::id, teams::name, teams::created_at) =
+    (teams::id, teams::name, teams::created_at);
+
+impl From<RowTeam> for Team {
+    fn from(r: RowTeam) -> Self {
+        Team {
+            id: r.id,
+            name: r.name,
+            created_at: r.created_at,
+        }
+    }
 }
 
 #[derive(Insertable)]
@@ -324,9 +1200,71 @@ struct NewUser<'a> {
     username: &'a str,
 }
 
+#[derive(QueryableByName)]
+struct RowTaskUsage {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    task_name: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    total_instances: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    running_instances: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    distinct_users: i64,
+}
+
+#[derive(Queryable)]
+struct RowAudit {
+    id: i32,
+    actor_id: i32,
+    action: String,
+    target: String,
+    detail: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Queryable)]
+struct RowApiKey {
+    id: i32,
+    name: String,
+    scopes: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+type ApiKeyColumns = (
+    api_keys::id,
+    api_keys::name,
+    api_keys::scopes,
+    api_keys::created_at,
+    api_keys::expires_at,
+    api_keys::revoked_at,
+);
+const API_KEY_COLUMNS: ApiKeyColumns = (
+    api_keys::id,
+    api_keys::name,
+    api_keys::scopes,
+    api_keys::created_at,
+    api_keys::expires_at,
+    api_keys::revoked_at,
+);
+
+impl From<RowApiKey> for ApiKey {
+    fn from(r: RowApiKey) -> Self {
+        ApiKey {
+            id: r.id,
+            name: r.name,
+            scopes: ApiScope::parse_list(&r.scopes),
+            created_at: r.created_at,
+            expires_at: r.expires_at,
+            revoked: r.revoked_at.is_some(),
+        }
+    }
+}
+
 #[derive(Queryable)]
 struct RowSession {
-    id: String,
+    token_hash: String,
     user_id: i32,
     created_at: DateTime<Utc>,
     expires_at: DateTime<Utc>,
@@ -335,7 +1273,7 @@ struct RowSession {
 #[derive(Insertable)]
 #[diesel(table_name = schema::sessions)]
 struct NewSession<'a> {
-    id: &'a str,
+    token_hash: &'a str,
     user_id: i32,
     expires_at: DateTime<Utc>,
 }
@@ -350,6 +1288,12 @@ struct RowInstance {
     status: String,
     endpoint: String,
     user_id: i32,
+    team_id: Option<i32>,
+    flag: Option<String>,
+    extensions: i32,
+    stop_reason: Option<String>,
+    node: String,
+    endpoints: Vec<String>,
 }
 
 #[derive(Insertable)]
@@ -361,6 +1305,10 @@ struct NewInstance {
     status: String,
     endpoint: String,
     user_id: i32,
+    team_id: Option<i32>,
+    flag: Option<String>,
+    node: String,
+    endpoints: Vec<String>,
 }
 
 impl From<(&TaskInstance, i32)> for NewInstance {
@@ -372,11 +1320,40 @@ impl From<(&TaskInstance, i32)> for NewInstance {
             status: t.status.as_str().to_string(),
             user_id: uid,
             endpoint: t.endpoint.clone(),
+            team_id: t.team_id,
+            flag: t.flag.clone(),
+            node: t.node.clone(),
+            endpoints: t.endpoints.clone(),
         }
     }
 }
 
 
+#[derive(Queryable)]
+struct RowQueueEntry {
+    id: i64,
+    user_id: i32,
+    task_name: String,
+    team_id: Option<i32>,
+    created_at: DateTime<Utc>,
+    failure: Option<String>,
+    failed_at: Option<DateTime<Utc>>,
+}
+
+impl From<RowQueueEntry> for QueueEntry {
+    fn from(r: RowQueueEntry) -> Self {
+        QueueEntry {
+            id: r.id,
+            user_id: r.user_id,
+            task_name: r.task_name,
+            team_id: r.team_id,
+            created_at: r.created_at,
+            failure: r.failure,
+            failed_at: r.failed_at,
+        }
+    }
+}
+
 impl From<RowInstance> for TaskInstance {
     fn from(r: RowInstance) -> Self {
         TaskInstance {
@@ -388,10 +1365,17 @@ impl From<RowInstance> for TaskInstance {
             status: match r.status.as_str() {
                 "Running" => InstanceStatus::Running,
                 "Stopped" => InstanceStatus::Stopped,
+                "Failed" => InstanceStatus::Failed,
                 _ => InstanceStatus::Expired,
             },
             endpoint: r.endpoint,
             user_id: r.user_id,
+            team_id: r.team_id,
+            flag: r.flag,
+            extensions: r.extensions,
+            stop_reason: r.stop_reason.as_deref().and_then(StopReason::parse),
+            node: r.node,
+            endpoints: r.endpoints,
         }
     }
 }
@@ -1,6 +1,7 @@
-use data_models::Db;
-use common::{TaskInstance, InstanceStatus};
+use data_models::{Db, InstanceFilter, NewSubmission};
+use common::{ApiScope, TaskInstance, InstanceStatus, Role, StopReason};
 use chrono::Utc;
+use config_manager::TaskConfig;
 use diesel::prelude::*;
 use diesel::sql_query;
 use diesel::sql_types::Text;
@@ -21,21 +22,31 @@ fn test_crud_instance() {
             .expect("insert task");
     }
 
+    let user = db.find_or_create_user("integration_user").expect("user");
+
     // Prepare a TaskInstance struct
     let now = Utc::now();
     let inst = TaskInstance {
         id: 0,
         task_name: "foo_task".into(),
         container_id: "abc123".into(),
-        port: 3000,
         created_at: now,
         expires_at: now + chrono::Duration::minutes(30),
         status: InstanceStatus::Running,
+        user_id: user.id,
+        endpoint: "http://abc123.ctf.local".into(),
+        team_id: None,
+        flag: Some("CTF{integration}".into()),
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: vec!["http://abc123-admin.ctf.local".into()],
     };
 
     // Create
-    let created = db.create_instance(&inst).expect("create");
+    let created = db.create_instance_for_user(&inst, user.id).expect("create");
     assert!(created.id > 0);
+    assert_eq!(created.endpoints, inst.endpoints);
 
     // List
     let all = db.list_instances().expect("list");
@@ -44,6 +55,373 @@ fn test_crud_instance() {
     // Update
     db.update_instance(created.id, InstanceStatus::Stopped, Utc::now())
         .expect("update");
-    let fetched = db.find_by_port(3000).expect("find").unwrap();
+    let fetched = db.find_instance_by_id(created.id).expect("find").unwrap();
     assert_eq!(fetched.status, InstanceStatus::Stopped);
+
+    let by_flag = db.find_instance_by_flag("CTF{integration}").expect("find").unwrap();
+    assert_eq!(by_flag.flag.as_deref(), Some("CTF{integration}"));
+}
+
+#[test]
+fn test_extend_counts_and_guards_races() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let user = db.find_or_create_user("extend_user").expect("user");
+
+    let now = Utc::now();
+    let inst = TaskInstance {
+        id: 0,
+        task_name: "foo_task".into(),
+        container_id: "extend123".into(),
+        created_at: now,
+        expires_at: now + chrono::Duration::minutes(30),
+        status: InstanceStatus::Running,
+        user_id: user.id,
+        endpoint: String::new(),
+        team_id: None,
+        flag: None,
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: Vec::new(),
+    };
+    let created = db.create_instance_for_user(&inst, user.id).expect("create");
+    assert_eq!(created.extensions, 0);
+
+    let later = created.expires_at + chrono::Duration::minutes(10);
+    assert!(db.extend_instance(created.id, 0, later).expect("extend"));
+    assert!(!db.extend_instance(created.id, 0, later).expect("stale extend"));
+
+    let fetched = db.find_instance_by_id(created.id).expect("find").unwrap();
+    assert_eq!(fetched.extensions, 1);
+
+    db.update_instance_status(created.id, InstanceStatus::Stopped).expect("stop");
+}
+
+#[test]
+fn test_fail_lost_instance_only_if_unchanged() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let user = db.find_or_create_user("lost_user").expect("user");
+
+    let now = Utc::now();
+    let inst = TaskInstance {
+        id: 0,
+        task_name: "foo_task".into(),
+        container_id: "lost-old".into(),
+        created_at: now,
+        expires_at: now + chrono::Duration::minutes(30),
+        status: InstanceStatus::Running,
+        user_id: user.id,
+        endpoint: String::new(),
+        team_id: None,
+        flag: None,
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: Vec::new(),
+    };
+    let created = db.create_instance_for_user(&inst, user.id).expect("create");
+
+    // A reset replaced the container after reconciliation read the row.
+    db.replace_instance_container(created.id, "lost-new").expect("replace");
+    assert!(!db.fail_lost_instance(created.id, "lost-old").expect("stale"));
+    assert!(db.fail_lost_instance(created.id, "lost-new").expect("fail"));
+    assert!(!db.fail_lost_instance(created.id, "lost-new").expect("already failed"));
+
+    let fetched = db.find_instance_by_id(created.id).expect("find").unwrap();
+    assert_eq!(fetched.status, InstanceStatus::Failed);
+}
+
+#[test]
+fn test_session_token_is_hashed() {
+    let db = Db::new().expect("DB init failed");
+    let user = db.find_or_create_user("session_user").expect("user");
+
+    let token = format!("token-{}", Utc::now().timestamp_nanos_opt().unwrap());
+    db.create_session(&token, user.id, Utc::now() + chrono::Duration::hours(1))
+        .expect("create session");
+
+    let found = db.validate_session(&token).expect("validate").expect("session");
+    assert_eq!(found.id, user.id);
+
+    let sess = db.get_session(&token).expect("get").expect("session");
+    assert_ne!(sess.token_hash, token);
+
+    assert!(db.validate_session(&sess.token_hash).expect("validate").is_none());
+}
+
+#[test]
+fn test_roles_and_task_owner() {
+    let db = Db::new().expect("DB init failed");
+
+    let player = db.find_or_create_user("role_player").expect("user");
+    assert_eq!(player.role, Role::Player);
+
+    let author = db.set_user_role("role_author", Role::Author).expect("set role");
+    assert_eq!(author.role, Role::Author);
+
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    assert!(db.set_task_owner("foo_task", author.id).expect("owner"));
+    assert_eq!(db.task_owner("foo_task").expect("owner"), Some(author.id));
+    assert!(!db.set_task_owner("no_such_task", author.id).expect("owner"));
+}
+
+#[test]
+fn test_admin_queries_and_bans() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let user = db.find_or_create_user("admin_target").expect("user");
+
+    let now = Utc::now();
+    let inst = TaskInstance {
+        id: 0,
+        task_name: "foo_task".into(),
+        container_id: "filter123".into(),
+        created_at: now,
+        expires_at: now + chrono::Duration::minutes(30),
+        status: InstanceStatus::Running,
+        user_id: user.id,
+        endpoint: String::new(),
+        team_id: None,
+        flag: None,
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: Vec::new(),
+    };
+    let created = db.create_instance_for_user(&inst, user.id).expect("create");
+
+    let filter = InstanceFilter {
+        task_name: Some("foo_task".into()),
+        user_id: Some(user.id),
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    };
+    let found = db.list_instances_filtered(&filter).expect("filter");
+    assert!(found.iter().any(|i| i.id == created.id));
+
+    let usage = db.task_usage().expect("usage");
+    assert!(usage.iter().any(|u| u.task_name == "foo_task" && u.running_instances >= 1));
+
+    let token = format!("ban-{}", now.timestamp_nanos_opt().unwrap());
+    db.create_session(&token, user.id, now + chrono::Duration::hours(1)).expect("session");
+    db.set_user_banned(user.id, true).expect("ban");
+    assert!(db.validate_session(&token).expect("validate").is_none());
+    db.set_user_banned(user.id, false).expect("unban");
+    assert!(db.validate_session(&token).expect("validate").is_some());
+
+    db.record_audit(user.id, "test", "user:admin_target", "").expect("audit");
+    assert!(db.list_audit(10).expect("audit").iter().any(|a| a.action == "test"));
+
+    db.update_instance_status(created.id, InstanceStatus::Stopped).expect("stop");
+}
+
+#[test]
+fn test_team_membership_and_visibility() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let suffix = Utc::now().timestamp_nanos_opt().unwrap();
+    let alice = db.find_or_create_user(&format!("team_alice_{}", suffix)).expect("user");
+    let bob = db.find_or_create_user(&format!("team_bob_{}", suffix)).expect("user");
+
+    let team = db.create_team(&format!("team_{}", suffix), "code", alice.id).expect("team");
+    assert!(db.join_team(bob.id, &team.name, "wrong").expect("join").is_none());
+    assert!(db.join_team(bob.id, &team.name, "code").expect("join").is_some());
+    assert_eq!(db.team_members(team.id).expect("members").len(), 2);
+
+    let now = Utc::now();
+    let inst = TaskInstance {
+        id: 0,
+        task_name: "foo_task".into(),
+        container_id: "team123".into(),
+        created_at: now,
+        expires_at: now + chrono::Duration::minutes(30),
+        status: InstanceStatus::Running,
+        user_id: alice.id,
+        endpoint: String::new(),
+        team_id: Some(team.id),
+        flag: None,
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: Vec::new(),
+    };
+    let created = db.create_instance_for_user(&inst, alice.id).expect("create");
+    assert_eq!(db.count_running_instances_for_team(team.id).expect("count"), 1);
+
+    let bob = db.find_user(&bob.username).expect("find").expect("bob");
+    assert!(bob.can_manage(&created));
+    let visible = db.list_instances_visible_to(&bob).expect("visible");
+    assert!(visible.iter().any(|i| i.id == created.id));
+
+    db.leave_team(bob.id).expect("leave");
+    let bob = db.find_user(&bob.username).expect("find").expect("bob");
+    assert!(!bob.can_manage(&created));
+
+    db.update_instance_status(created.id, InstanceStatus::Stopped).expect("stop");
+}
+
+#[test]
+fn test_api_keys() {
+    let db = Db::new().expect("DB init failed");
+    let suffix = Utc::now().timestamp_nanos_opt().unwrap();
+    let user = db.find_or_create_user(&format!("key_user_{}", suffix)).expect("user");
+
+    let key = format!("ctfk_{}", suffix);
+    let info = db
+        .create_api_key(user.id, "script", &key, &[ApiScope::Deploy, ApiScope::Read], None)
+        .expect("create key");
+    assert_eq!(info.scopes, vec![ApiScope::Deploy, ApiScope::Read]);
+
+    let (owner, scopes) = db.validate_api_key(&key).expect("validate").expect("live key");
+    assert_eq!(owner.id, user.id);
+    assert!(scopes.contains(&ApiScope::Deploy));
+
+    let expired = format!("ctfk_expired_{}", suffix);
+    db.create_api_key(user.id, "old", &expired, &[], Some(Utc::now() - chrono::Duration::seconds(1)))
+        .expect("create key");
+    assert!(db.validate_api_key(&expired).expect("validate").is_none());
+
+    assert!(db.revoke_api_key(user.id, info.id).expect("revoke"));
+    assert!(db.validate_api_key(&key).expect("validate").is_none());
+    assert!(!db.revoke_api_key(user.id, info.id).expect("revoke"));
+    assert_eq!(db.list_api_keys(user.id).expect("list").len(), 2);
+}
+
+#[test]
+fn test_submissions_and_solves() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let suffix = Utc::now().timestamp_nanos_opt().unwrap();
+    let user = db.find_or_create_user(&format!("sub_user_{}", suffix)).expect("user");
+    let since = Utc::now() - chrono::Duration::minutes(1);
+
+    for _ in 0..2 {
+        db.record_submission(&NewSubmission {
+            user_id: user.id,
+            team_id: None,
+            task_name: "foo_task",
+            submitted: "CTF{wrong}",
+            correct: false,
+            shared_from_instance: None,
+        })
+        .expect("submission");
+    }
+    assert_eq!(db.count_recent_wrong_submissions(user.id, "foo_task", since).expect("count"), 2);
+
+    assert!(db.record_solve(user.id, None, "foo_task").expect("solve"));
+    assert!(!db.record_solve(user.id, None, "foo_task").expect("solve"));
+}
+
+#[test]
+fn test_scheduler_lease() {
+    let db = Db::new().expect("DB init failed");
+    let name = format!("lease_{}", Utc::now().timestamp_nanos_opt().unwrap());
+
+    assert!(db.try_acquire_lease(&name, "a", 30).expect("acquire"));
+    assert!(db.try_acquire_lease(&name, "a", 30).expect("renew"));
+    assert!(!db.try_acquire_lease(&name, "b", 30).expect("contend"));
+
+    assert!(!db.release_lease(&name, "b").expect("release"));
+    assert!(db.release_lease(&name, "a").expect("release"));
+    assert!(db.try_acquire_lease(&name, "b", 0).expect("take over"));
+    // A zero-length lease lapses at once, so anyone may take it.
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert!(db.try_acquire_lease(&name, "a", 30).expect("expired"));
+    db.release_lease(&name, "a").expect("cleanup");
+}
+
+#[test]
+fn test_instance_events_dedupe_and_visibility() {
+    let db = Db::new().expect("DB init failed");
+    db.ensure_task("foo_task", "./tasks/foo_task/Dockerfile", &TaskConfig::default()).expect("task");
+    let owner = db.find_or_create_user("events_owner").expect("user");
+    let other = db.find_or_create_user("events_other").expect("user");
+
+    let now = Utc::now();
+    let inst = TaskInstance {
+        id: 0,
+        task_name: "foo_task".into(),
+        container_id: "events123".into(),
+        created_at: now,
+        expires_at: now + chrono::Duration::minutes(5),
+        status: InstanceStatus::Running,
+        user_id: owner.id,
+        endpoint: String::new(),
+        team_id: None,
+        flag: None,
+        extensions: 0,
+        stop_reason: None,
+        node: "local".into(),
+        endpoints: Vec::new(),
+    };
+    let created = db.create_instance_for_user(&inst, owner.id).expect("create");
+    let cursor = db.latest_instance_event_id().expect("latest");
+    let key = format!("expiry_warning:{}:300", created.id);
+
+    let ev = db
+        .record_instance_event(&created, "expiry_warning", "expires soon", Some(&key))
+        .expect("record")
+        .expect("first insert");
+    assert!(db.record_instance_event(&created, "expiry_warning", "again", Some(&key)).expect("dup").is_none());
+
+    let seen = db.list_instance_events_for(&owner, cursor, 100).expect("list");
+    assert_eq!(seen.iter().map(|e| e.id).collect::<Vec<_>>(), vec![ev.id]);
+    assert_eq!(seen[0].task_name, "foo_task");
+    assert!(db.list_instance_events_for(&other, cursor, 100).expect("list").is_empty());
+    assert!(db.list_instance_events_for(&owner, ev.id, 100).expect("list").is_empty());
+
+    db.stop_instance(created.id, StopReason::Idle).expect("stop");
+    let fetched = db.find_instance_by_id(created.id).expect("find").unwrap();
+    assert_eq!(fetched.status, InstanceStatus::Stopped);
+    assert_eq!(fetched.stop_reason, Some(StopReason::Idle));
+}
+
+#[test]
+fn test_deploy_queue_order_and_places() {
+    let db = Db::new().expect("DB init failed");
+    let first = db.find_or_create_user("queue_first").expect("user");
+    let second = db.find_or_create_user("queue_second").expect("user");
+    db.leave_deploy_queue(first.id).expect("reset");
+    db.leave_deploy_queue(second.id).expect("reset");
+
+    let a = db.enqueue_deploy(first.id, "foo_task", None).expect("enqueue");
+    let b = db.enqueue_deploy(second.id, "foo_task", None).expect("enqueue");
+    assert!(a.id < b.id);
+    // A second request keeps the original place.
+    assert_eq!(db.enqueue_deploy(first.id, "bar_pwn", None).expect("again").id, a.id);
+
+    let (_, pos_a) = db.queue_position(first.id).expect("pos").unwrap();
+    let (_, pos_b) = db.queue_position(second.id).expect("pos").unwrap();
+    assert_eq!(pos_b, pos_a + 1);
+
+    assert!(db.remove_queue_entry(a.id).expect("remove"));
+    assert_eq!(db.queue_position(second.id).expect("pos").unwrap().1, pos_a);
+    assert!(db.leave_deploy_queue(second.id).expect("leave"));
+    assert!(db.queue_position(second.id).expect("pos").is_none());
+}
+
+#[test]
+fn test_failed_queued_deploy_stays_visible() {
+    let db = Db::new().expect("DB init failed");
+    let user = db.find_or_create_user("queue_failed").expect("user");
+    db.leave_deploy_queue(user.id).expect("reset");
+
+    let entry = db.enqueue_deploy(user.id, "foo_task", None).expect("enqueue");
+    // Claimed by the worker, then the deploy fails.
+    assert!(db.remove_queue_entry(entry.id).expect("claim"));
+    assert!(!db.remove_queue_entry(entry.id).expect("claimed once"));
+    db.fail_queued_deploy(&entry, "deploy failed").expect("fail");
+
+    let (failed, pos) = db.queue_position(user.id).expect("pos").unwrap();
+    assert_eq!(pos, 0);
+    assert_eq!(failed.failure.as_deref(), Some("deploy failed"));
+    assert!(db.list_deploy_queue().expect("list").iter().all(|e| e.user_id != user.id));
+
+    // Queueing again replaces the failure with a fresh place.
+    let again = db.enqueue_deploy(user.id, "foo_task", None).expect("again");
+    assert!(again.failure.is_none());
+    assert!(db.queue_position(user.id).expect("pos").unwrap().1 > 0);
+    db.leave_deploy_queue(user.id).expect("leave");
 }
@@ -0,0 +1,224 @@
+//! Admission against `[capacity]`, per-task and per-node capacity limits,
+//! counting what running instances reserve through their container limits,
+//! and placement of new instances on nodes.
+
+use common::TaskInstance;
+use config_manager::{Capacity, Config, Node};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Resources reserved by a set of instances.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Load {
+    pub instances: u32,
+    pub memory: i64,
+    pub cpus: f64,
+}
+
+impl Load {
+    /// What one instance of `task` reserves, over all its containers.
+    pub fn of_task(cfg: &Config, task: &str) -> Load {
+        let task_cfg = cfg.task(task);
+        let mut load = Load { instances: 1, ..Default::default() };
+        let services = task_cfg.services.values().map(|s| &s.containers);
+        for overrides in std::iter::once(&task_cfg.containers).chain(services) {
+            let cont = cfg.containers.with_overrides(overrides);
+            load.memory += cont.memory_limit;
+            load.cpus += cont.cpu_quota;
+        }
+        load
+    }
+
+    pub fn add(&mut self, other: &Load) {
+        self.instances += other.instances;
+        self.memory += other.memory;
+        self.cpus += other.cpus;
+    }
+
+    /// The first limit in `cap` that `self` plus `extra` would exceed.
+    fn exceeds(&self, extra: &Load, cap: &Capacity) -> Option<&'static str> {
+        if cap.max_instances > 0 && self.instances + extra.instances > cap.max_instances {
+            Some("instances")
+        } else if cap.max_memory > 0 && self.memory + extra.memory > cap.max_memory {
+            Some("memory")
+        } else if cap.max_cpus > 0.0 && self.cpus + extra.cpus > cap.max_cpus {
+            Some("CPU")
+        } else {
+            None
+        }
+    }
+
+    /// How full `cap` would be with `extra` added, as the largest fraction
+    /// over its set limits; 0 without limits.
+    fn fill(&self, extra: &Load, cap: &Capacity) -> f64 {
+        let mut fill: f64 = 0.0;
+        if cap.max_instances > 0 {
+            fill = fill.max((self.instances + extra.instances) as f64 / cap.max_instances as f64);
+        }
+        if cap.max_memory > 0 {
+            fill = fill.max((self.memory + extra.memory) as f64 / cap.max_memory as f64);
+        }
+        if cap.max_cpus > 0.0 {
+            fill = fill.max((self.cpus + extra.cpus) / cap.max_cpus);
+        }
+        fill
+    }
+}
+
+/// Current load of running instances, overall, per task and per node.
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub total: Load,
+    pub tasks: HashMap<String, Load>,
+    pub nodes: HashMap<String, Load>,
+}
+
+/// Why a deploy cannot start now.
+#[derive(Debug, PartialEq)]
+pub enum Blocked {
+    /// Global capacity is full; waits behind everything queued before it.
+    Global(&'static str),
+    /// The task's own capacity is full; other tasks may go ahead.
+    Task(&'static str),
+    /// One instance alone is over a limit, or no node matches the task's
+    /// labels, so waiting will not help.
+    Never(&'static str),
+}
+
+impl Usage {
+    pub fn of(cfg: &Config, running: &[TaskInstance]) -> Usage {
+        let mut usage = Usage::default();
+        for inst in running {
+            usage.reserve(cfg, &inst.task_name, &inst.node);
+        }
+        usage
+    }
+
+    /// Count one more instance of `task` on `node`.
+    pub fn reserve(&mut self, cfg: &Config, task: &str, node: &str) {
+        let one = Load::of_task(cfg, task);
+        self.add(task, &one);
+        self.nodes.entry(node.to_string()).or_default().add(&one);
+    }
+
+    /// Whether one more instance of `task` fits, and on which node.
+    /// `healthy` reports whether a node can take deploys right now.
+    pub fn admit(&self, cfg: &Config, task: &str, healthy: impl Fn(&str) -> bool) -> Result<String, Blocked> {
+        let one = Load::of_task(cfg, task);
+        let task_cfg = cfg.task(task);
+        self.check(task, &one, &cfg.capacity, &task_cfg.capacity)?;
+        self.place(cfg.nodes(), &task_cfg.node_labels, &one, healthy)
+    }
+
+    /// The least loaded usable node carrying `labels` with room for `one`.
+    pub fn place(
+        &self,
+        nodes: &[Node],
+        labels: &HashMap<String, String>,
+        one: &Load,
+        healthy: impl Fn(&str) -> bool,
+    ) -> Result<String, Blocked> {
+        let matching: Vec<&Node> = nodes
+            .iter()
+            .filter(|n| labels.iter().all(|(k, v)| n.labels.get(k) == Some(v)))
+            .collect();
+        let empty = Load::default();
+        if matching.iter().all(|n| empty.exceeds(one, &n.capacity).is_some()) {
+            return Err(Blocked::Never("matching node"));
+        }
+        matching
+            .into_iter()
+            .filter(|n| !n.drain && healthy(&n.name))
+            .filter_map(|n| {
+                let load = self.nodes.get(&n.name).unwrap_or(&empty);
+                load.exceeds(one, &n.capacity).is_none().then(|| (load.fill(one, &n.capacity), load.instances, n))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.name.cmp(&b.2.name)))
+            .map(|(_, _, n)| n.name.clone())
+            // Nodes reserved for some labels only hold up tasks wanting them.
+            .ok_or(if labels.is_empty() { Blocked::Global("node capacity") } else { Blocked::Task("node capacity") })
+    }
+
+    /// [`Usage::admit`] with the instance footprint and limits spelled out.
+    pub fn check(&self, task: &str, one: &Load, global: &Capacity, task_cap: &Capacity) -> Result<(), Blocked> {
+        let empty = Load::default();
+        if let Some(what) = empty.exceeds(one, global).or_else(|| empty.exceeds(one, task_cap)) {
+            return Err(Blocked::Never(what));
+        }
+        let task_load = self.tasks.get(task).unwrap_or(&empty);
+        if let Some(what) = task_load.exceeds(one, task_cap) {
+            return Err(Blocked::Task(what));
+        }
+        if let Some(what) = self.total.exceeds(one, global) {
+            return Err(Blocked::Global(what));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, task: &str, one: &Load) {
+        self.total.add(one);
+        self.tasks.entry(task.to_string()).or_default().add(one);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_respects_global_and_task_limits() {
+        let one = Load { instances: 1, memory: 512 << 20, cpus: 0.5 };
+        let global = Capacity { max_instances: 3, max_memory: 0, max_cpus: 0.0 };
+        let task_cap = Capacity { max_instances: 2, ..Default::default() };
+
+        let mut usage = Usage::default();
+        assert_eq!(usage.check("web", &one, &global, &task_cap), Ok(()));
+        usage.add("web", &one);
+        usage.add("web", &one);
+        assert_eq!(usage.check("web", &one, &global, &task_cap), Err(Blocked::Task("instances")));
+        usage.add("pwn", &one);
+        let free = Capacity::default();
+        assert_eq!(usage.check("pwn", &one, &global, &free), Err(Blocked::Global("instances")));
+
+        let cpus = Capacity { max_cpus: 1.0, ..Default::default() };
+        assert_eq!(Usage::default().check("web", &one, &cpus, &free), Ok(()));
+        assert_eq!(usage.check("web", &one, &cpus, &free), Err(Blocked::Global("CPU")));
+        let small = Capacity { max_memory: 256 << 20, ..Default::default() };
+        assert_eq!(Usage::default().check("web", &one, &free, &small), Err(Blocked::Never("memory")));
+    }
+
+    fn node(name: &str, labels: &[(&str, &str)], max_instances: u32) -> Node {
+        Node {
+            name: name.into(),
+            url: String::new(),
+            tls: None,
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            capacity: Capacity { max_instances, ..Default::default() },
+            drain: false,
+            router: None,
+        }
+    }
+
+    #[test]
+    fn placement_prefers_free_matching_nodes() {
+        let one = Load { instances: 1, memory: 512 << 20, cpus: 0.5 };
+        let mut nodes = vec![node("a", &[], 2), node("b", &[], 4), node("gpu", &[("gpu", "yes")], 1)];
+        let none = HashMap::new();
+        let gpu: HashMap<String, String> = [("gpu".to_string(), "yes".to_string())].into();
+        let all = |_: &str| true;
+
+        let mut usage = Usage::default();
+        // a would be 1/2 full, b 1/4, gpu full.
+        assert_eq!(usage.place(&nodes, &none, &one, all), Ok("b".into()));
+        assert_eq!(usage.place(&nodes, &gpu, &one, all), Ok("gpu".into()));
+        usage.nodes.insert("gpu".into(), one.clone());
+        assert_eq!(usage.place(&nodes, &none, &one, |n: &str| n == "a"), Ok("a".into()));
+        assert_eq!(usage.place(&nodes, &gpu, &one, all), Err(Blocked::Task("node capacity")));
+
+        nodes[1].drain = true;
+        usage.nodes.insert("a".into(), Load { instances: 2, ..one.clone() });
+        assert_eq!(usage.place(&nodes, &none, &one, all), Err(Blocked::Global("node capacity")));
+        let other: HashMap<String, String> = [("region".to_string(), "eu".to_string())].into();
+        assert_eq!(usage.place(&nodes, &other, &one, all), Err(Blocked::Never("matching node")));
+    }
+}
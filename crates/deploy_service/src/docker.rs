@@ -1,10 +1,19 @@
 use crate::error::DeployError;
-use bollard::Docker;
+use config_manager::{Node, get_config};
+use bollard::{API_DEFAULT_VERSION, Docker};
 use bollard::auth::DockerCredentials;
-use bollard::models::{ContainerCreateBody, HostConfig, PortBinding};
-use bollard::query_parameters::{BuildImageOptions, RestartContainerOptions};
+use bollard::errors::Error as BollardError;
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::models::{
+    ContainerCreateBody, ContainerInspectResponse, ContainerStatsResponse, ContainerSummary, Ipam, IpamConfig, Network,
+    NetworkConnectRequest, NetworkCreateRequest, NetworkDisconnectRequest,
+};
+use bollard::query_parameters::{
+    BuildImageOptions, CreateImageOptions, InspectContainerOptions, LogsOptions, RestartContainerOptions, UploadToContainerOptions,
+};
 use bollard::query_parameters::{
-    CreateContainerOptions, RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
+    CreateContainerOptions, ListContainersOptions, ListNetworksOptions, RemoveContainerOptions, StartContainerOptions,
+    StatsOptions, StopContainerOptions,
 };
 use bytes::Bytes;
 use futures_util::{Stream, TryStreamExt};
@@ -24,13 +33,42 @@ pub struct DockerClient {
     inner: Docker, // keep this private
 }
 
+/// Outcome of [`DockerClient::ensure_network`].
+#[derive(Debug, PartialEq)]
+pub enum Ensured {
+    Created,
+    Existed,
+    /// The subnet overlaps a network the daemon already has.
+    SubnetTaken,
+}
+
+/// Seconds before a request to a daemon times out.
+const TIMEOUT_SECS: u64 = 120;
+
 impl DockerClient {
-    pub fn new() -> Self {
-        let docker = Docker::connect_with_local_defaults().unwrap();
-        Self { inner: docker }
+    /// Connect to `node` over the transport its URL names.
+    pub fn connect(node: &Node) -> Result<Self, DeployError> {
+        let url = node.url.as_str();
+        let docker = match (&node.tls, url.split_once("://").map(|(scheme, _)| scheme)) {
+            (_, None) if url.is_empty() => Docker::connect_with_local_defaults()?,
+            (Some(tls), _) => {
+                Docker::connect_with_ssl(url, &tls.key, &tls.cert, &tls.ca, TIMEOUT_SECS, API_DEFAULT_VERSION)?
+            }
+            (None, Some("ssh")) => Docker::connect_with_ssh(url, TIMEOUT_SECS, API_DEFAULT_VERSION)?,
+            (None, Some("unix")) => Docker::connect_with_unix(url, TIMEOUT_SECS, API_DEFAULT_VERSION)?,
+            (None, Some("tcp" | "http")) => Docker::connect_with_http(url, TIMEOUT_SECS, API_DEFAULT_VERSION)?,
+            _ => return Err(DeployError::Config(format!("node {}: unsupported url {:?}", node.name, url))),
+        };
+        Ok(Self { inner: docker })
     }
 
-    pub async fn build_image(&self, task_name: &str, tag: &str) -> Result<(), DeployError> {
+    pub async fn ping(&self) -> Result<(), DeployError> {
+        self.inner.ping().await?;
+        Ok(())
+    }
+
+    /// Build `tag` from `context`, a directory under the tasks directory.
+    pub async fn build_image(&self, context: &str, tag: &str) -> Result<(), DeployError> {
         let options = BuildImageOptions {
             dockerfile: "Dockerfile".to_string(),
             t: Some(tag.to_string()),
@@ -41,7 +79,7 @@ impl DockerClient {
         let mut tar_buf = Vec::new();
         {
             let mut tar = TarBuilder::new(&mut tar_buf);
-            tar.append_dir_all(".", format!("tasks/{}", task_name))?;
+            tar.append_dir_all(".", get_config().tasks_dir.join(context))?;
             tar.finish()?;
         }
         let full = Full::from(Bytes::from(tar_buf));
@@ -60,6 +98,26 @@ impl DockerClient {
         }
         Ok(())
     }
+    /// Pull `image` from its registry.
+    pub async fn pull_image(&self, image: &str) -> Result<(), DeployError> {
+        let options = CreateImageOptions { from_image: Some(image.to_string()), ..Default::default() };
+        let mut pull_stream = self.inner.create_image(Some(options), None, None);
+        while let Some(chunk) = pull_stream.try_next().await? {
+            if let Some(err_msg) = chunk.error {
+                return Err(DeployError::Build(err_msg));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn image_exists(&self, tag: &str) -> Result<bool, DeployError> {
+        match self.inner.inspect_image(tag).await {
+            Ok(_) => Ok(true),
+            Err(BollardError::DockerResponseServerError { status_code: 404, .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn create_container(
         &self,
         opts: CreateContainerOptions,
@@ -77,6 +135,38 @@ impl DockerClient {
         Ok(())
     }
 
+    /// Write a single file into a created (not necessarily started) container.
+    pub async fn upload_file(
+        &self,
+        container_id: &str,
+        path: &str,
+        contents: &[u8],
+    ) -> Result<(), DeployError> {
+        let target = std::path::Path::new(path);
+        let (dir, name) = match (target.parent(), target.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(DeployError::Config(format!("invalid file path {}", path))),
+        };
+
+        let mut tar_buf = Vec::new();
+        {
+            let mut tar = TarBuilder::new(&mut tar_buf);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o444);
+            header.set_cksum();
+            tar.append_data(&mut header, name, contents)?;
+            tar.finish()?;
+        }
+        let body: BodyType = Either::Left(Full::from(Bytes::from(tar_buf)));
+        let options = UploadToContainerOptions {
+            path: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        self.inner.upload_to_container(container_id, Some(options), body).await?;
+        Ok(())
+    }
+
     pub async fn stop_container(&self, container_id: &str) -> Result<(), DeployError> {
         let _ = self.inner.stop_container(container_id, None::<StopContainerOptions>).await;
         Ok(())
@@ -85,11 +175,134 @@ impl DockerClient {
     pub async fn remove_container(&self, container_id: &str) -> Result<(), DeployError> {
         let _ = self.inner.remove_container(
             container_id,
-            Some(RemoveContainerOptions { force: true, ..Default::default() }),
+            Some(RemoveContainerOptions { force: true, v: true, ..Default::default() }),
         ).await;
         Ok(())
     }
 
+    /// Create a bridge network on `subnet` unless it already exists.
+    /// Containers on an `internal` network cannot reach outside it.
+    pub async fn ensure_network(
+        &self,
+        name: &str,
+        labels: HashMap<String, String>,
+        internal: bool,
+        subnet: &str,
+    ) -> Result<Ensured, DeployError> {
+        let ipam = Ipam {
+            config: Some(vec![IpamConfig { subnet: Some(subnet.to_string()), ..Default::default() }]),
+            ..Default::default()
+        };
+        let request = NetworkCreateRequest {
+            name: name.to_string(),
+            driver: Some("bridge".into()),
+            internal: Some(internal),
+            labels: Some(labels),
+            ipam: Some(ipam),
+            ..Default::default()
+        };
+        match self.inner.create_network(request).await {
+            Ok(_) => Ok(Ensured::Created),
+            Err(BollardError::DockerResponseServerError { status_code: 409, .. }) => Ok(Ensured::Existed),
+            Err(BollardError::DockerResponseServerError { status_code: 403, message }) if message.contains("overlaps") => {
+                Ok(Ensured::SubnetTaken)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Subnets of every network on the daemon, ours or not.
+    pub async fn network_subnets(&self) -> Result<Vec<String>, DeployError> {
+        let networks = self.inner.list_networks(None::<ListNetworksOptions>).await?;
+        Ok(networks
+            .into_iter()
+            .filter_map(|n| n.ipam.and_then(|i| i.config))
+            .flatten()
+            .filter_map(|c| c.subnet)
+            .collect())
+    }
+
+    /// Networks carrying `label`.
+    pub async fn list_networks(&self, label: &str) -> Result<Vec<Network>, DeployError> {
+        let filters = HashMap::from([("label".to_string(), vec![label.to_string()])]);
+        Ok(self.inner.list_networks(Some(ListNetworksOptions { filters: Some(filters) })).await?)
+    }
+
+    /// Remove a network; fails while containers are still attached.
+    pub async fn remove_network(&self, name: &str) -> Result<(), DeployError> {
+        self.inner.remove_network(name).await?;
+        Ok(())
+    }
+
+    /// Attach a container to a further network.
+    pub async fn connect_network(&self, network: &str, container_id: &str) -> Result<(), DeployError> {
+        let request = NetworkConnectRequest { container: Some(container_id.to_string()), ..Default::default() };
+        self.inner.connect_network(network, request).await?;
+        Ok(())
+    }
+
+    /// Detach a container from a network, even a stopped one.
+    pub async fn disconnect_network(&self, network: &str, container: &str) -> Result<(), DeployError> {
+        let request = NetworkDisconnectRequest { container: Some(container.to_string()), force: Some(true) };
+        self.inner.disconnect_network(network, request).await?;
+        Ok(())
+    }
+
+    /// Ids of the containers, running or not, carrying `label` = `value`.
+    pub async fn containers_labelled(&self, label: &str, value: &str) -> Result<Vec<String>, DeployError> {
+        let filters = HashMap::from([("label".to_string(), vec![format!("{}={}", label, value)])]);
+        let opts = ListContainersOptions { all: true, filters: Some(filters), ..Default::default() };
+        let list = self.inner.list_containers(Some(opts)).await?;
+        Ok(list.into_iter().filter_map(|c| c.id).collect())
+    }
+
+    pub async fn inspect_container(&self, container_id: &str) -> Result<ContainerInspectResponse, DeployError> {
+        Ok(self.inner.inspect_container(container_id, None::<InspectContainerOptions>).await?)
+    }
+
+    /// Every container on the host, running or not.
+    pub async fn list_containers(&self) -> Result<Vec<ContainerSummary>, DeployError> {
+        let opts = ListContainersOptions { all: true, ..Default::default() };
+        Ok(self.inner.list_containers(Some(opts)).await?)
+    }
+
+    /// A single stats sample, without waiting for a second one to compute
+    /// rates.
+    pub async fn stats(&self, container_id: &str) -> Result<ContainerStatsResponse, DeployError> {
+        let opts = StatsOptions { stream: false, one_shot: true };
+        self.inner
+            .stats(container_id, Some(opts))
+            .try_next()
+            .await?
+            .ok_or_else(|| DeployError::Config(format!("no stats for container {}", container_id)))
+    }
+
+    /// The last `tail` lines of stdout and stderr.
+    pub async fn logs(&self, container_id: &str, tail: u32) -> Result<String, DeployError> {
+        let opts = LogsOptions { stdout: true, stderr: true, tail: tail.to_string(), ..Default::default() };
+        let chunks: Vec<_> = self.inner.logs(container_id, Some(opts)).try_collect().await?;
+        Ok(chunks.iter().map(|c| String::from_utf8_lossy(c.as_ref()).into_owned()).collect())
+    }
+
+    /// Run `cmd` in a running container; returns its exit code and its
+    /// stdout and stderr together.
+    pub async fn exec(&self, container: &str, cmd: Vec<String>) -> Result<(i64, String), DeployError> {
+        let opts = CreateExecOptions {
+            cmd: Some(cmd),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+        let exec = self.inner.create_exec(container, opts).await?;
+        let mut output = String::new();
+        if let StartExecResults::Attached { output: stream, .. } = self.inner.start_exec(&exec.id, None).await? {
+            let chunks: Vec<_> = stream.try_collect().await?;
+            output = chunks.iter().map(|c| String::from_utf8_lossy(c.as_ref()).into_owned()).collect();
+        }
+        let code = self.inner.inspect_exec(&exec.id).await?.exit_code.unwrap_or(-1);
+        Ok((code, output))
+    }
+
     pub async fn restart_container(&self, container_id: &str) -> Result<(), DeployError> {
         self.inner.restart_container(container_id, None::<RestartContainerOptions>).await?;
         Ok(())
@@ -15,4 +15,11 @@ pub enum DeployError {
     /// Configuration or routing‐variant error
     #[error("configuration error: {0}")]
     Config(String),
+    /// The readiness probe did not pass in time; `logs` holds the tail of
+    /// the container's output.
+    #[error("instance not ready: {reason}")]
+    NotReady { reason: String, logs: String },
+    /// A per-instance limit (extensions, lifetime) refused the request.
+    #[error("{0}")]
+    Limit(String),
 }
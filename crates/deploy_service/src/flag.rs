@@ -0,0 +1,65 @@
+use crate::error::DeployError;
+use config_manager::{FlagConfig, FlagMode};
+use uuid::Uuid;
+
+/// Who an instance's flag is derived for; team deploys share one flag.
+pub fn flag_owner(user_id: i32, team_id: Option<i32>) -> String {
+    match team_id {
+        Some(tid) => format!("team:{}", tid),
+        None => format!("user:{}", user_id),
+    }
+}
+
+/// Flag for a new instance of `task_name`, or `None` for static tasks.
+pub fn generate_flag(
+    cfg: &FlagConfig,
+    secret: &str,
+    task_name: &str,
+    owner: &str,
+) -> Result<Option<String>, DeployError> {
+    let body = match cfg.mode {
+        FlagMode::Static => return Ok(None),
+        FlagMode::Random => Uuid::new_v4().simple().to_string(),
+        FlagMode::Hmac => {
+            if secret.is_empty() {
+                return Err(DeployError::Config(
+                    "flags.secret must be set for hmac flags".into(),
+                ));
+            }
+            let digest = common::hash_token(secret, &format!("{}:{}", owner, task_name));
+            digest[..32].to_string()
+        }
+    };
+    Ok(Some(format!("{}{{{}}}", cfg.prefix, body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(mode: FlagMode) -> FlagConfig {
+        FlagConfig { mode, ..Default::default() }
+    }
+
+    #[test]
+    fn hmac_flags_are_stable_per_owner() {
+        let c = cfg(FlagMode::Hmac);
+        let a = generate_flag(&c, "k", "foo_task", &flag_owner(1, None)).unwrap().unwrap();
+        let b = generate_flag(&c, "k", "foo_task", &flag_owner(1, None)).unwrap().unwrap();
+        let other = generate_flag(&c, "k", "foo_task", &flag_owner(2, None)).unwrap().unwrap();
+        let team = generate_flag(&c, "k", "foo_task", &flag_owner(1, Some(1))).unwrap().unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, other);
+        assert_ne!(a, team);
+        assert!(a.starts_with("CTF{") && a.ends_with('}'));
+    }
+
+    #[test]
+    fn static_and_misconfigured_modes() {
+        assert!(generate_flag(&cfg(FlagMode::Static), "k", "t", "user:1").unwrap().is_none());
+        assert!(generate_flag(&cfg(FlagMode::Hmac), "", "t", "user:1").is_err());
+        let r1 = generate_flag(&cfg(FlagMode::Random), "", "t", "user:1").unwrap();
+        let r2 = generate_flag(&cfg(FlagMode::Random), "", "t", "user:1").unwrap();
+        assert_ne!(r1, r2);
+    }
+}
@@ -0,0 +1,343 @@
+//! The containers of one instance: the task's own and, for multi-container
+//! tasks, one per `[tasks.<name>.services.*]` entry. Members carry the
+//! instance's router name as [`LABEL_GROUP`] and share a private network
+//! named after it, on its own subnet from `routing.subnet_pool`, on which
+//! each is reachable by its service name. Besides them only the router
+//! joins that network, so instances cannot reach one another; with egress
+//! blocked the network is internal and has no route out. Creating a group
+//! removes whatever it made when a step fails; the other operations act on
+//! every member.
+
+use crate::docker::{DockerClient, Ensured};
+use crate::error::DeployError;
+use crate::{LABEL_GROUP, LABEL_MANAGED, LABEL_SERVICE, LABEL_TASK, LABEL_TEAM, LABEL_USER, host_config};
+use bollard::models::{ContainerCreateBody, EndpointSettings, NetworkingConfig};
+use bollard::query_parameters::{CreateContainerOptions, StartContainerOptions};
+use config_manager::{Cidr, ContainerOverrides, Egress, MAIN_SERVICE, Service, get_config};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Free subnets tried before giving up on creating a network.
+const SUBNET_ATTEMPTS: usize = 5;
+
+/// The Traefik router name of an instance: the first label of its hostname.
+/// Doubles as the group name of its containers.
+pub(crate) fn router_name(hostname: &str) -> &str {
+    hostname.split('.').next().unwrap_or(hostname)
+}
+
+/// The private network of a group.
+pub(crate) fn network_name(group: &str) -> String {
+    format!("ctf-{}", group)
+}
+
+/// Hostname of an exposed service, next to the instance's own.
+pub(crate) fn service_hostname(hostname: &str, service: &str) -> String {
+    match hostname.split_once('.') {
+        Some((first, rest)) => format!("{}-{}.{}", first, service, rest),
+        None => format!("{}-{}", hostname, service),
+    }
+}
+
+/// What players connect to for a container routed at `hostname`.
+pub(crate) fn endpoint(protocol: &str, hostname: &str) -> String {
+    if protocol == "http" {
+        format!("http://{}", hostname)
+    } else {
+        format!("nc {} {}", hostname, 9000)
+    }
+}
+
+pub(crate) fn task_image(task_name: &str) -> String {
+    format!("ctf-{}", task_name)
+}
+
+/// Image of a service: pulled as named, or built from its directory.
+pub(crate) fn service_image(task_name: &str, service: &str, s: &Service) -> String {
+    match &s.image {
+        Some(image) => image.clone(),
+        None => format!("{}/{}", task_image(task_name), service),
+    }
+}
+
+/// One container of a group.
+struct Member<'a> {
+    service: &'a str,
+    image: String,
+    /// Build context under the tasks directory, for built images.
+    build: Option<String>,
+    env: &'a HashMap<String, String>,
+    overrides: &'a ContainerOverrides,
+    /// Protocol, container port and hostname when routed by Traefik.
+    route: Option<(&'a str, u16, String)>,
+    flag: bool,
+}
+
+/// A created, not yet started group.
+pub(crate) struct Created {
+    /// The task's own container first.
+    pub ids: Vec<String>,
+    /// Endpoints of exposed services.
+    pub endpoints: Vec<String>,
+}
+
+/// Owner of the containers, for their labels.
+pub(crate) struct Owner {
+    pub user_id: i32,
+    pub team_id: Option<i32>,
+}
+
+/// Create every container of an instance of `task_name` routed at
+/// `hostname`, flags in place, without starting them. On failure nothing
+/// created here is left behind.
+pub(crate) async fn create(
+    docker: &DockerClient,
+    task_name: &str,
+    owner: &Owner,
+    hostname: &str,
+    flag: Option<&str>,
+    router: &str,
+) -> Result<Created, DeployError> {
+    let cfg = get_config();
+    let task_cfg = cfg.task(task_name);
+    let group = router_name(hostname);
+
+    let mut members = vec![Member {
+        service: MAIN_SERVICE,
+        image: task_image(task_name),
+        build: Some(task_name.to_string()),
+        env: &task_cfg.env,
+        overrides: &task_cfg.containers,
+        route: Some((&task_cfg.protocol, task_cfg.container_port, hostname.to_string())),
+        flag: true,
+    }];
+    let mut endpoints = Vec::new();
+    for (service, s) in &task_cfg.services {
+        let route = s.expose.then(|| (s.protocol.as_str(), s.container_port, service_hostname(hostname, service)));
+        if let Some((protocol, _, host)) = &route {
+            endpoints.push(endpoint(protocol, host));
+        }
+        members.push(Member {
+            service,
+            image: service_image(task_name, service, s),
+            build: s.build.as_ref().map(|dir| format!("{}/{}", task_name, dir)),
+            env: &s.env,
+            overrides: &s.containers,
+            route,
+            flag: s.flag,
+        });
+    }
+
+    // A reset reuses the network, which the router is already on.
+    let net = network_name(group);
+    let internal = task_cfg.egress(&cfg.routing) == Egress::Block;
+    let new_net = ensure_network(docker, group, internal).await?;
+    if new_net && let Err(e) = docker.connect_network(&net, router).await {
+        let _ = docker.remove_network(&net).await;
+        return Err(e);
+    }
+    let mut ids = Vec::new();
+    for m in &members {
+        match create_member(docker, task_name, owner, group, m, flag).await {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                remove_containers(docker, &ids).await;
+                if new_net {
+                    remove_network(docker, router, group).await;
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(Created { ids, endpoints })
+}
+
+/// Create the group's network on the first subnet of `routing.subnet_pool`
+/// not in use on the daemon; true if it did not exist yet. Another deploy
+/// may take a subnet between listing and creating, so a few are tried.
+async fn ensure_network(docker: &DockerClient, group: &str, internal: bool) -> Result<bool, DeployError> {
+    let cfg = get_config();
+    let pool = cfg.routing.subnets().map_err(DeployError::Config)?;
+    let used: Vec<Cidr> = docker.network_subnets().await?.iter().filter_map(|s| Cidr::parse(s).ok()).collect();
+    let labels = HashMap::from([
+        (LABEL_MANAGED.to_string(), "true".to_string()),
+        (LABEL_GROUP.to_string(), group.to_string()),
+    ]);
+    let free = pool.iter().filter(|c| !used.iter().any(|u| u.overlaps(c)));
+    for subnet in free.take(SUBNET_ATTEMPTS) {
+        match docker.ensure_network(&network_name(group), labels.clone(), internal, &subnet.to_string()).await? {
+            Ensured::Created => return Ok(true),
+            Ensured::Existed => return Ok(false),
+            Ensured::SubnetTaken => continue,
+        }
+    }
+    Err(DeployError::Config(format!(
+        "routing.subnet_pool {} has no free /{} subnet left",
+        cfg.routing.subnet_pool, cfg.routing.subnet_prefix
+    )))
+}
+
+async fn create_member(
+    docker: &DockerClient,
+    task_name: &str,
+    owner: &Owner,
+    group: &str,
+    m: &Member<'_>,
+    flag: Option<&str>,
+) -> Result<String, DeployError> {
+    let cfg = get_config();
+    let task_cfg = cfg.task(task_name);
+    let cont_cfg = cfg.containers.with_overrides(m.overrides);
+    let flag = flag.filter(|_| m.flag);
+    if flag.is_some() && task_cfg.flag.file.is_some() && cont_cfg.read_only_rootfs {
+        return Err(DeployError::Config(format!(
+            "{}: flag.file needs a writable root filesystem",
+            m.service
+        )));
+    }
+
+    // Built images are rebuilt every time, so edits to the task directory
+    // reach new instances; the build cache keeps this cheap.
+    match &m.build {
+        Some(context) => docker.build_image(context, &m.image).await?,
+        None if !docker.image_exists(&m.image).await? => docker.pull_image(&m.image).await?,
+        None => {}
+    }
+
+    let mut labels = HashMap::new();
+    labels.insert(LABEL_MANAGED.into(), "true".into());
+    labels.insert(LABEL_TASK.into(), task_name.to_string());
+    labels.insert(LABEL_USER.into(), owner.user_id.to_string());
+    if let Some(tid) = owner.team_id {
+        labels.insert(LABEL_TEAM.into(), tid.to_string());
+    }
+    labels.insert(LABEL_GROUP.into(), group.to_string());
+    labels.insert(LABEL_SERVICE.into(), m.service.to_string());
+    if let Some((protocol, port, hostname)) = &m.route {
+        // no published ports, just labels; the router is on the network
+        let router = router_name(hostname);
+        let (kind, rule, entry) = if *protocol == "http" {
+            ("http", format!("Host(`{}`)", hostname), &cfg.routing.http_entry)
+        } else {
+            ("tcp", format!("HostSNI(`{}`)", hostname), &cfg.routing.tcp_entry)
+        };
+        labels.insert("traefik.enable".into(), "true".into());
+        labels.insert("traefik.docker.network".into(), network_name(group));
+        labels.insert(format!("traefik.{}.routers.{}.rule", kind, router), rule);
+        labels.insert(format!("traefik.{}.routers.{}.entrypoints", kind, router), entry.clone());
+        labels.insert(
+            format!("traefik.{}.services.{}.loadbalancer.server.port", kind, router),
+            port.to_string(),
+        );
+    }
+
+    let net = network_name(group);
+    let mut hc = host_config(&cont_cfg, m.overrides);
+    hc.network_mode = Some(net.clone());
+    let settings = EndpointSettings { aliases: Some(vec![m.service.to_string()]), ..Default::default() };
+    let networking = NetworkingConfig { endpoints_config: Some(HashMap::from([(net, settings)])) };
+    let mut env: Vec<String> = m.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    if let (Some(f), Some(var)) = (flag, &task_cfg.flag.env) {
+        env.push(format!("{}={}", var, f));
+    }
+    let opts = CreateContainerOptions {
+        name: Some(format!("ctf-{}-{}", task_name, Uuid::new_v4())),
+        platform: "".to_string(),
+    };
+    let body = ContainerCreateBody {
+        image: Some(m.image.clone()),
+        env: Some(env),
+        labels: Some(labels),
+        host_config: Some(hc),
+        networking_config: Some(networking),
+        ..Default::default()
+    };
+    let id = docker.create_container(opts, body).await?;
+
+    if let (Some(f), Some(path)) = (flag, &task_cfg.flag.file)
+        && let Err(e) = docker.upload_file(&id, path, f.as_bytes()).await
+    {
+        let _ = docker.remove_container(&id).await;
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Start a created group, its services before the task's own container.
+/// On failure the whole group is removed.
+pub(crate) async fn start(docker: &DockerClient, router: &str, group: &str, ids: &[String]) -> Result<(), DeployError> {
+    for id in ids.iter().skip(1).chain(ids.first()) {
+        if let Err(e) = docker.start_container(id, None::<StartContainerOptions>).await {
+            remove(docker, router, group, ids).await;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Restart every member, services first.
+pub(crate) async fn restart(docker: &DockerClient, ids: &[String]) -> Result<(), DeployError> {
+    for id in ids.iter().skip(1).chain(ids.first()) {
+        docker.restart_container(id).await?;
+    }
+    Ok(())
+}
+
+/// The containers of a group, with `main` first. `main` is included even
+/// without a group label, as on containers created before groups existed.
+pub(crate) async fn members(docker: &DockerClient, group: Option<&str>, main: &str) -> Result<Vec<String>, DeployError> {
+    let mut ids = vec![main.to_string()];
+    if let Some(group) = group {
+        ids.extend(docker.containers_labelled(LABEL_GROUP, group).await?.into_iter().filter(|id| id != main));
+    }
+    Ok(ids)
+}
+
+/// Stop and remove `ids` and the group's network.
+pub(crate) async fn remove(docker: &DockerClient, router: &str, group: &str, ids: &[String]) {
+    remove_containers(docker, ids).await;
+    remove_network(docker, router, group).await;
+}
+
+/// Detach the router from the group's network and remove it. Fails
+/// harmlessly when the network is gone, or was made before every instance
+/// had one.
+pub(crate) async fn remove_network(docker: &DockerClient, router: &str, group: &str) {
+    let net = network_name(group);
+    let _ = docker.disconnect_network(&net, router).await;
+    let _ = docker.remove_network(&net).await;
+}
+
+pub(crate) async fn remove_containers(docker: &DockerClient, ids: &[String]) {
+    for id in ids {
+        let _ = docker.stop_container(id).await;
+        let _ = docker.remove_container(id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn services_get_hostnames_next_to_the_instance() {
+        let host = "0f3a9c.ctf.example.org";
+        assert_eq!(service_hostname(host, "bot"), "0f3a9c-bot.ctf.example.org");
+        assert_eq!(router_name(&service_hostname(host, "bot")), "0f3a9c-bot");
+        assert_eq!(endpoint("tcp", "0f3a9c-db.ctf.example.org"), "nc 0f3a9c-db.ctf.example.org 9000");
+
+        let pulled = Service {
+            image: Some("redis:7-alpine".into()),
+            build: None,
+            env: HashMap::new(),
+            expose: false,
+            protocol: "http".into(),
+            container_port: 3000,
+            flag: false,
+            containers: ContainerOverrides::default(),
+        };
+        assert_eq!(service_image("notes", "db", &pulled), "redis:7-alpine");
+        let built = Service { image: None, build: Some("bot".into()), ..pulled };
+        assert_eq!(service_image("notes", "bot", &built), "ctf-notes/bot");
+    }
+}
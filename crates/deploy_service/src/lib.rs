@@ -1,20 +1,227 @@
+pub mod capacity;
 mod docker;
 pub mod error;
+pub mod flag;
+mod group;
+mod nodes;
+mod probe;
 
 use crate::error::DeployError;
-use bollard::models::{ContainerCreateBody, HostConfig};
-use bollard::query_parameters::CreateContainerOptions;
-use bollard::query_parameters::StartContainerOptions;
-use chrono::Utc;
-use common::{InstanceStatus, TaskInstance, compute_expiry};
-use config_manager::get_config;
+use bollard::models::{ContainerSummary, HostConfig, Network, Mount, MountTypeEnum, ResourcesUlimits};
+use chrono::{DateTime, Utc};
+use common::{InstanceStatus, StopReason, TaskInstance, compute_expiry};
+use config_manager::{ContainerConfig, ContainerOverrides, Ports, TaskConfig, get_config};
 use data_models::Db;
-use docker::DockerClient;
+use nodes::NodePool;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, warn};
 use uuid::Uuid;
 
+/// Build the Docker `HostConfig` from the task's effective container settings
+/// (`cont_cfg`, already merged) and its task-only extras.
+fn host_config(cont_cfg: &ContainerConfig, extra: &ContainerOverrides) -> HostConfig {
+    let cpu_period = 100_000;
+    let cpu_quota = (cont_cfg.cpu_quota * cpu_period as f64) as i64;
+
+    let mut security_opt = vec![if cont_cfg.enable_no_new_privileges {
+        "no-new-privileges".to_string()
+    } else {
+        "no-new-privileges=false".to_string()
+    }];
+    security_opt.extend(extra.security_opt.iter().cloned());
+
+    let mut tmpfs = HashMap::new();
+    if cont_cfg.enable_tmpfs {
+        tmpfs.insert("/tmp".to_string(), format!("rw,size={}", cont_cfg.tmpfs_size));
+    }
+    tmpfs.extend(extra.tmpfs.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    let ulimits: Vec<ResourcesUlimits> = extra
+        .ulimits
+        .iter()
+        .map(|u| ResourcesUlimits {
+            name: Some(u.name.clone()),
+            soft: Some(u.soft),
+            hard: Some(u.hard),
+        })
+        .collect();
+
+    // Anonymous volumes: writable even under a read-only rootfs, and removed
+    // together with the container.
+    let mounts: Vec<Mount> = extra
+        .writable_paths
+        .iter()
+        .map(|path| Mount {
+            target: Some(path.clone()),
+            typ: Some(MountTypeEnum::VOLUME),
+            read_only: Some(false),
+            ..Default::default()
+        })
+        .collect();
+
+    HostConfig {
+        memory: Some(cont_cfg.memory_limit),
+        memory_swap: Some(cont_cfg.swap_limit),
+        cpu_period: Some(cpu_period),
+        cpu_quota: Some(cpu_quota),
+        pids_limit: Some(cont_cfg.pids_limit as i64),
+
+        readonly_rootfs: Some(cont_cfg.read_only_rootfs),
+        cap_drop: if cont_cfg.drop_all_capabilities {
+            Some(vec!["ALL".into()])
+        } else {
+            None
+        },
+        cap_add: if cont_cfg.add_capabilities.is_empty() {
+            None
+        } else {
+            Some(cont_cfg.add_capabilities.clone())
+        },
+        security_opt: Some(security_opt),
+        tmpfs: if tmpfs.is_empty() { None } else { Some(tmpfs) },
+        ulimits: if ulimits.is_empty() { None } else { Some(ulimits) },
+        mounts: if mounts.is_empty() { None } else { Some(mounts) },
+        ..Default::default()
+    }
+}
+
+fn lifetime_cap(inst: &TaskInstance, max_lifetime_secs: u64) -> DateTime<Utc> {
+    inst.created_at + chrono::Duration::seconds(max_lifetime_secs as i64)
+}
+
+/// Extensions `inst` has left under `task_cfg`.
+pub fn extensions_left(inst: &TaskInstance, task_cfg: &TaskConfig, ports: &Ports) -> u32 {
+    task_cfg
+        .max_extensions(ports)
+        .saturating_sub(inst.extensions.max(0) as u32)
+}
+
+/// Expiry after one more player extension, or why it is refused.
+fn extension_expiry(
+    inst: &TaskInstance,
+    task_cfg: &TaskConfig,
+    ports: &Ports,
+) -> Result<DateTime<Utc>, DeployError> {
+    if inst.status != InstanceStatus::Running {
+        return Err(DeployError::Limit("instance is not running".into()));
+    }
+    if extensions_left(inst, task_cfg, ports) == 0 {
+        return Err(DeployError::Limit("no extensions left".into()));
+    }
+    let cap = lifetime_cap(inst, task_cfg.max_lifetime_secs(ports));
+    if inst.expires_at >= cap {
+        return Err(DeployError::Limit("maximum lifetime reached".into()));
+    }
+    let extra = chrono::Duration::seconds(task_cfg.extend_time_secs(ports) as i64);
+    Ok((inst.expires_at + extra).min(cap))
+}
+
+/// Marks containers created by [`Deployer::deploy`].
+pub const LABEL_MANAGED: &str = "ctf.managed";
+pub const LABEL_TASK: &str = "ctf.task";
+pub const LABEL_USER: &str = "ctf.user";
+pub const LABEL_TEAM: &str = "ctf.team";
+/// Shared by the containers of one instance.
+pub const LABEL_GROUP: &str = "ctf.group";
+pub const LABEL_SERVICE: &str = "ctf.service";
+
+/// A container as seen by reconciliation.
+#[derive(Debug, Clone)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub node: String,
+    pub created_at: DateTime<Utc>,
+    /// Carries [`LABEL_MANAGED`], so it is ours to remove.
+    pub managed: bool,
+    pub task_name: Option<String>,
+    pub user_id: Option<i32>,
+    pub group: Option<String>,
+}
+
+/// Cumulative resource counters of a container, for idle detection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub cpu_nanos: u64,
+    /// Received plus sent, over all interfaces.
+    pub net_bytes: u64,
+}
+
+fn container_infos(node: &str, list: Vec<ContainerSummary>) -> impl Iterator<Item = ContainerInfo> + '_ {
+    list.into_iter().filter_map(move |c| {
+        let labels = c.labels.unwrap_or_default();
+        Some(ContainerInfo {
+            id: c.id?,
+            node: node.to_string(),
+            created_at: DateTime::from_timestamp(c.created.unwrap_or(0), 0).unwrap_or_default(),
+            managed: labels.contains_key(LABEL_MANAGED),
+            task_name: labels.get(LABEL_TASK).cloned(),
+            user_id: labels.get(LABEL_USER).and_then(|u| u.parse().ok()),
+            group: labels.get(LABEL_GROUP).cloned(),
+        })
+    })
+}
+
+/// The hostname of an instance from its endpoint, `http://<host>` or
+/// `nc <host> <port>`.
+fn endpoint_hostname(endpoint: &str) -> Option<&str> {
+    endpoint
+        .strip_prefix("http://")
+        .or_else(|| endpoint.strip_prefix("nc ").and_then(|r| r.split(' ').next()))
+        .filter(|h| !h.is_empty())
+}
+
+/// The [`LABEL_GROUP`] of an instance's containers.
+pub fn instance_group(inst: &TaskInstance) -> Option<&str> {
+    endpoint_hostname(&inst.endpoint).map(group::router_name)
+}
+
+/// An instance network as seen by reconciliation.
+#[derive(Debug, Clone)]
+pub struct NetworkInfo {
+    pub name: String,
+    pub node: String,
+    pub created_at: DateTime<Utc>,
+    pub group: Option<String>,
+}
+
+fn network_infos(node: &str, list: Vec<Network>) -> impl Iterator<Item = NetworkInfo> + '_ {
+    list.into_iter().filter_map(move |n| {
+        let created = n.created.as_deref().and_then(|c| DateTime::parse_from_rfc3339(c).ok());
+        Some(NetworkInfo {
+            name: n.name?,
+            node: node.to_string(),
+            created_at: created.map(|c| c.with_timezone(&Utc)).unwrap_or_default(),
+            group: n.labels.unwrap_or_default().remove(LABEL_GROUP),
+        })
+    })
+}
+
+/// Containers and instance networks on every node that answered, for
+/// reconciliation.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub containers: Vec<ContainerInfo>,
+    pub networks: Vec<NetworkInfo>,
+    /// Nodes whose containers were listed; instances on other nodes cannot
+    /// be judged.
+    pub reachable: Vec<String>,
+}
+
+/// A node as shown to admins.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatus {
+    pub name: String,
+    pub labels: HashMap<String, String>,
+    pub drain: bool,
+    pub healthy: bool,
+}
+
+/// Cheap to clone; clones share their node clients.
+#[derive(Clone)]
 pub struct Deployer {
-    docker: DockerClient,
+    nodes: Arc<NodePool>,
     db: Db,
 }
 pub struct DeployResult {
@@ -22,163 +229,272 @@ pub struct DeployResult {
 }
 impl Deployer {
     pub async fn new() -> Result<Self, DeployError> {
-        let docker = DockerClient::new();
         let db = Db::new()?;
-        Ok(Self { docker, db })
+        Ok(Self { nodes: Arc::default(), db })
     }
 
-    pub async fn deploy(&mut self, task_name: &str) -> Result<DeployResult, DeployError> {
+    /// Deploy `task_name` on `node`, as chosen by
+    /// [`capacity::Usage::admit`]. The instance is started but not probed
+    /// yet; save it, then hand it out after [`Deployer::wait_ready`].
+    pub async fn deploy(
+        &self,
+        task_name: &str,
+        user_id: i32,
+        team_id: Option<i32>,
+        node: &str,
+    ) -> Result<DeployResult, DeployError> {
         let cfg = get_config();
-        let task_cfg = cfg.tasks.get(task_name).unwrap_or(&cfg.tasks["_default"]);
-
+        let docker = &self.nodes.get(node)?.docker;
+        let task_cfg = cfg.task(task_name);
 
-        // 2. Build the image
-        let tag = format!("ctf-{}-{}", task_name, Uuid::new_v4());
-        self.docker.build_image(task_name, &tag).await?;
+        let owner = flag::flag_owner(user_id, team_id);
+        let flag = flag::generate_flag(&task_cfg.flag, cfg.flags.secret.expose(), task_name, &owner)?;
 
-        // 3. Generate a unique token & hostname (for Traefik mode)
+        // Generate a unique token & hostname (for Traefik mode)
         let unique = Uuid::new_v4().simple().to_string();
         let hostname = format!("{}.{}", unique, cfg.routing.traefik_domain);
 
-        // 4. Create the container
-        let container_id = {
-                // no published ports, just labels + custom network
-                let mut labels = HashMap::new();
-                labels.insert("traefik.enable".into(), "true".into());
-                labels.insert(format!("traefik.docker.network"), "ctf-net".into());
-
-                if task_cfg.protocol == "http" {
-                    // HTTP router
-                    labels.insert(
-                        format!("traefik.http.routers.{}.rule", unique),
-                        format!("Host(`{}`)", hostname),
-                    );
-                    labels.insert(
-                        format!("traefik.http.routers.{}.entrypoints", unique),
-                        cfg.routing.http_entry.clone(),
-                    );
-                    labels.insert(
-                        format!("traefik.http.services.{}.loadbalancer.server.port", unique),
-                        task_cfg.container_port.to_string(),
-                    );
-                } else {
-                    // TCP router
-                    labels.insert(
-                        format!("traefik.tcp.routers.{}.rule", unique),
-                        format!("HostSNI(`{}`)", hostname),
-                    );
-                    labels.insert(
-                        format!("traefik.tcp.routers.{}.entrypoints", unique),
-                        cfg.routing.tcp_entry.clone(),
-                    );
-                    labels.insert(
-                        format!("traefik.tcp.services.{}.loadbalancer.server.port", unique),
-                        task_cfg.container_port.to_string(),
-                    );
-                }
-                let cont_cfg = &get_config().containers;
-
-                let cpu_period = 100_000;
-                let cpu_quota = (cont_cfg.cpu_quota * cpu_period as f64) as i64;
-
-                let mut hc = HostConfig {
-                    memory: Some(cont_cfg.memory_limit),
-                    memory_swap: Some(cont_cfg.swap_limit),
-                    cpu_period: Some(cpu_period),
-                    cpu_quota: Some(cpu_quota),
-                    pids_limit: Some(cont_cfg.pids_limit as i64),
-
-                    readonly_rootfs: Some(cont_cfg.read_only_rootfs),
-                    cap_drop: if cont_cfg.drop_all_capabilities {
-                        Some(vec!["ALL".into()])
-                    } else {
-                        None
-                    },
-                    cap_add: if cont_cfg.add_capabilities.is_empty() {
-                        None
-                    } else {
-                        Some(cont_cfg.add_capabilities.clone())
-                    },
-                    security_opt: Some(vec![if cont_cfg.enable_no_new_privileges {
-                        "no-new-privileges".to_string()
-                    } else {
-                        "no-new-privileges=false".to_string()
-                    }]),
-                    tmpfs: if cont_cfg.enable_tmpfs {
-                        let mut m = HashMap::new();
-                        m.insert(
-                            "/tmp".to_string(),
-                            format!("rw,size={}", cont_cfg.tmpfs_size),
-                        );
-                        Some(m)
-                    } else {
-                        None
-                    },
-                    ..Default::default()
-                };
-                let opts = CreateContainerOptions {
-                    name: Some(tag.clone()),
-                    platform: "".to_string(),
-                };
-                let body = ContainerCreateBody {
-                    image: Some(tag.clone()),
-                    labels: Some(labels),
-                    host_config: Some(hc),
-                    ..Default::default()
-                };
-                self.docker.create_container(opts, body).await?
-            };
-
-
-        self.docker
-            .start_container(&container_id, None::<StartContainerOptions>)
-            .await?;
-
-        let endpoint = {
-                if task_cfg.protocol == "http" {
-                    format!("http://{}", hostname)
-                } else {
-                    format!("nc {} {}", hostname, 9000)
-                }
-            };
-
+        let owner = group::Owner { user_id, team_id };
+        let router = cfg.router_container(node);
+        let created = group::create(docker, task_name, &owner, &hostname, flag.as_deref(), router).await?;
+        group::start(docker, router, &unique, &created.ids).await?;
+        let container_id = created.ids[0].clone();
+        let endpoint = group::endpoint(&task_cfg.protocol, &hostname);
 
         let inst = TaskInstance {
             id: 0,
             task_name: task_name.to_string(),
             container_id: container_id.clone(),
             created_at: Utc::now(),
-            expires_at: compute_expiry(cfg.ports.default_ttl_secs),
+            expires_at: compute_expiry(task_cfg.ttl_secs(&cfg.ports)),
             status: InstanceStatus::Running,
             endpoint: endpoint.clone(),
-            user_id: 0, // will be set by create_instance_for_user
+            user_id,
+            team_id,
+            flag,
+            extensions: 0,
+            stop_reason: None,
+            node: node.to_string(),
+            endpoints: created.endpoints,
         };
 
         Ok(DeployResult { instance: inst })
     }
 
-    pub async fn stop(&mut self, inst: &TaskInstance) -> Result<(), DeployError> {
-        let _ = self.docker.stop_container(&inst.container_id).await;
-        self.docker.remove_container(&inst.container_id).await?;
-        self.db
-            .update_instance(inst.id, InstanceStatus::Stopped, Utc::now())?;
+    /// Wait until a saved instance, fresh from [`Deployer::deploy`] or
+    /// [`Deployer::reset`], passes its task's readiness probe. This can take
+    /// `readiness.timeout_secs`, so call it on a clone without holding the
+    /// deployer lock. An instance that fails is removed and marked failed.
+    pub async fn wait_ready(&self, inst: &TaskInstance) -> Result<(), DeployError> {
+        let cfg = get_config();
+        let task_cfg = cfg.task(&inst.task_name);
+        let group = instance_group(inst)
+            .ok_or_else(|| DeployError::Config(format!("cannot route endpoint {}", inst.endpoint)))?;
+        let docker = &self.nodes.get(&inst.node)?.docker;
+        let router = cfg.router_container(&inst.node);
+        let network = group::network_name(group);
+        let port = task_cfg.container_port;
+        let Err(e) = probe::wait_ready(docker, &inst.container_id, &network, port, &task_cfg.readiness, router).await
+        else {
+            return Ok(());
+        };
+        if let DeployError::NotReady { reason, logs } = &e {
+            warn!("{} instance {} on {} not ready: {}\n{}", inst.task_name, inst.id, inst.node, reason, logs);
+        }
+        let ids = group::members(docker, Some(group), &inst.container_id)
+            .await
+            .unwrap_or_else(|_| vec![inst.container_id.clone()]);
+        group::remove(docker, router, group, &ids).await;
+        self.db.update_instance_status(inst.id, InstanceStatus::Failed)?;
+        Err(e)
+    }
+
+    /// Rebuild the task's images, its own and those of built services, on
+    /// every node so the next deploy picks up changes to `tasks/<name>/`.
+    /// Running instances keep their old images. Returns the first failure
+    /// after trying all nodes.
+    pub async fn rebuild(&mut self, task_name: &str) -> Result<(), DeployError> {
+        let cfg = get_config();
+        let mut images = vec![(task_name.to_string(), group::task_image(task_name))];
+        for (service, s) in &cfg.task(task_name).services {
+            if let Some(dir) = &s.build {
+                images.push((format!("{}/{}", task_name, dir), group::service_image(task_name, service, s)));
+            }
+        }
+        let mut result = Ok(());
+        for node in cfg.nodes() {
+            let built = match self.nodes.get(&node.name) {
+                Ok(h) => {
+                    let mut built = Ok(());
+                    for (context, image) in &images {
+                        built = built.and(h.docker.build_image(context, image).await);
+                    }
+                    built
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = built {
+                warn!("Rebuilding {} on node {} failed: {}", task_name, node.name, e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Remove every container of the instance, and its network.
+    pub async fn stop(&mut self, inst: &TaskInstance, reason: StopReason) -> Result<(), DeployError> {
+        let docker = &self.nodes.get(&inst.node)?.docker;
+        let group = instance_group(inst);
+        let ids = group::members(docker, group, &inst.container_id).await?;
+        match group {
+            Some(g) => group::remove(docker, get_config().router_container(&inst.node), g, &ids).await,
+            None => group::remove_containers(docker, &ids).await,
+        }
+        self.db.stop_instance(inst.id, reason)?;
         Ok(())
     }
+    /// Restart every container of the instance. The expiry stays as it is;
+    /// more time only comes from [`Deployer::extend`].
     pub async fn restart(&mut self, inst: &TaskInstance) -> Result<(), DeployError> {
-        self.docker.restart_container(&inst.container_id).await?;
-
-        let new_expiry = compute_expiry(get_config().ports.default_ttl_secs);
+        let docker = &self.nodes.get(&inst.node)?.docker;
+        let ids = group::members(docker, instance_group(inst), &inst.container_id).await?;
+        group::restart(docker, &ids).await?;
         self.db
-            .update_instance(inst.id, InstanceStatus::Running, new_expiry)?;
+            .update_instance(inst.id, InstanceStatus::Running, inst.expires_at)?;
         Ok(())
     }
 
-    pub async fn extend(
-        &mut self,
-        inst: &TaskInstance,
-        extra_ttl_secs: u64,
-    ) -> Result<(), DeployError> {
-        let new_expiry = compute_expiry(extra_ttl_secs);
+    /// Replace the instance's containers with fresh ones from the task's
+    /// images, keeping its node, hostname, endpoints, flag and expiry. The
+    /// new containers are created before the old ones go, so a failed
+    /// create leaves the instance as it was; if they then fail to start,
+    /// nothing is left and the instance is marked failed. Follow with
+    /// [`Deployer::wait_ready`], as after a deploy.
+    pub async fn reset(&mut self, inst: &TaskInstance) -> Result<TaskInstance, DeployError> {
+        let hostname = endpoint_hostname(&inst.endpoint)
+            .ok_or_else(|| DeployError::Config(format!("cannot route endpoint {}", inst.endpoint)))?;
+        let group = group::router_name(hostname);
+        let docker = &self.nodes.get(&inst.node)?.docker;
+        let old = group::members(docker, Some(group), &inst.container_id).await?;
+        let owner = group::Owner { user_id: inst.user_id, team_id: inst.team_id };
+        let cfg = get_config();
+        let router = cfg.router_container(&inst.node);
+        let created = group::create(docker, &inst.task_name, &owner, hostname, inst.flag.as_deref(), router).await?;
+
+        group::remove_containers(docker, &old).await;
+        let container_id = created.ids[0].clone();
+        self.db.replace_instance_container(inst.id, &container_id)?;
+        if let Err(e) = group::start(docker, router, group, &created.ids).await {
+            self.db.update_instance_status(inst.id, InstanceStatus::Failed)?;
+            return Err(e);
+        }
+        Ok(TaskInstance { container_id, ..inst.clone() })
+    }
+
+    /// Player extension: add the task's `extend_time_secs` to the current
+    /// expiry, within its extension count and lifetime limits.
+    pub async fn extend(&mut self, inst: &TaskInstance) -> Result<DateTime<Utc>, DeployError> {
+        let cfg = get_config();
+        let new_expiry = extension_expiry(inst, cfg.task(&inst.task_name), &cfg.ports)?;
+        if !self.db.extend_instance(inst.id, inst.extensions, new_expiry)? {
+            return Err(DeployError::Limit("instance changed meanwhile, try again".into()));
+        }
+        Ok(new_expiry)
+    }
+
+    /// All containers and instance networks on every reachable node, with
+    /// the metadata set by `deploy`. The local daemon is listed even when
+    /// `[[nodes]]` leaves it out, so what was placed there before is still
+    /// reconciled; it is only skipped quietly if it does not answer.
+    pub async fn inventory(&self) -> Result<Inventory, DeployError> {
+        let cfg = get_config();
+        let implicit = cfg.implicit_local_node();
+        let mut inv = Inventory::default();
+        for node in cfg.nodes().iter().chain(implicit) {
+            let listed = match self.nodes.get(&node.name) {
+                Ok(h) => match h.docker.list_containers().await {
+                    Ok(containers) => h.docker.list_networks(LABEL_MANAGED).await.map(|n| (containers, n)),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match listed {
+                Ok((containers, networks)) => {
+                    inv.containers.extend(container_infos(&node.name, containers));
+                    inv.networks.extend(network_infos(&node.name, networks));
+                    inv.reachable.push(node.name.clone());
+                }
+                Err(e) if implicit.is_some_and(|n| n.name == node.name) => {
+                    debug!("Local daemon not listed: {}", e)
+                }
+                Err(e) => warn!("Cannot list containers on node {}: {}", node.name, e),
+            }
+        }
+        Ok(inv)
+    }
+
+    /// Ping nodes whose health is stale; call before placing instances.
+    pub async fn refresh_nodes(&self) {
+        self.nodes.refresh().await
+    }
+
+    pub fn node_healthy(&self, name: &str) -> bool {
+        self.nodes.is_healthy(name)
+    }
+
+    pub fn node_status(&self) -> Vec<NodeStatus> {
+        get_config()
+            .nodes()
+            .iter()
+            .map(|n| NodeStatus {
+                name: n.name.clone(),
+                labels: n.labels.clone(),
+                drain: n.drain,
+                healthy: self.nodes.is_healthy(&n.name),
+            })
+            .collect()
+    }
+
+    /// The last `tail` log lines of the instance's container.
+    pub async fn logs(&self, inst: &TaskInstance, tail: u32) -> Result<String, DeployError> {
+        self.nodes.get(&inst.node)?.docker.logs(&inst.container_id, tail).await
+    }
+
+    /// Current counters of the instance's container.
+    pub async fn usage(&self, inst: &TaskInstance) -> Result<Usage, DeployError> {
+        let stats = self.nodes.get(&inst.node)?.docker.stats(&inst.container_id).await?;
+        Ok(Usage {
+            cpu_nanos: stats
+                .cpu_stats
+                .and_then(|c| c.cpu_usage)
+                .and_then(|u| u.total_usage)
+                .unwrap_or(0),
+            net_bytes: stats
+                .networks
+                .unwrap_or_default()
+                .values()
+                .map(|n| n.rx_bytes.unwrap_or(0) + n.tx_bytes.unwrap_or(0))
+                .sum(),
+        })
+    }
+
+    /// Stop and remove a container no instance row refers to.
+    pub async fn remove_orphan(&self, c: &ContainerInfo) -> Result<(), DeployError> {
+        let docker = &self.nodes.get(&c.node)?.docker;
+        docker.stop_container(&c.id).await?;
+        docker.remove_container(&c.id).await
+    }
+
+    /// Detach the router from a network left behind and remove it.
+    pub async fn remove_network(&self, n: &NetworkInfo) -> Result<(), DeployError> {
+        let docker = &self.nodes.get(&n.node)?.docker;
+        let _ = docker.disconnect_network(&n.name, get_config().router_container(&n.node)).await;
+        docker.remove_network(&n.name).await
+    }
+
+    /// Admin extension by `secs` from the current expiry, ignoring limits.
+    pub async fn extend_by(&mut self, inst: &TaskInstance, secs: u64) -> Result<(), DeployError> {
+        let new_expiry = inst.expires_at + chrono::Duration::seconds(secs as i64);
         self.db.update_instance(inst.id, inst.status, new_expiry)?;
         Ok(())
     }
@@ -191,14 +507,85 @@ mod tests {
     use bollard::query_parameters::RemoveContainerOptions;
     use tokio::time::{Duration, sleep};
 
+    #[test]
+    fn task_overrides_reach_host_config() {
+        let cfg = get_config();
+        let global = &cfg.containers;
+        let overrides = ContainerOverrides {
+            pids_limit: Some(4242),
+            read_only_rootfs: Some(false),
+            add_capabilities: Some(vec!["CAP_SYS_PTRACE".into()]),
+            ulimits: vec![config_manager::Ulimit { name: "nofile".into(), soft: 64, hard: 128 }],
+            writable_paths: vec!["/data".into()],
+            ..Default::default()
+        };
+        let hc = host_config(&global.with_overrides(&overrides), &overrides);
+
+        assert_eq!(hc.pids_limit, Some(4242));
+        assert_eq!(hc.readonly_rootfs, Some(false));
+        assert_eq!(hc.memory, Some(global.memory_limit));
+        assert_eq!(hc.cap_add, Some(vec!["CAP_SYS_PTRACE".to_string()]));
+        assert_eq!(hc.ulimits.unwrap()[0].hard, Some(128));
+        assert_eq!(hc.mounts.unwrap()[0].target.as_deref(), Some("/data"));
+    }
+
+    #[test]
+    fn reset_keeps_the_hostname_of_the_endpoint() {
+        let host = "0f3a9c.ctf.example.org";
+        assert_eq!(endpoint_hostname(&format!("http://{}", host)), Some(host));
+        assert_eq!(endpoint_hostname(&format!("nc {} 9000", host)), Some(host));
+        assert_eq!(endpoint_hostname("tcp://elsewhere"), None);
+        assert_eq!(group::router_name(host), "0f3a9c");
+    }
+
+    #[test]
+    fn extensions_add_to_expiry_within_limits() {
+        let cfg = get_config();
+        let task = TaskConfig {
+            extend_time_secs: Some(600),
+            max_extensions: Some(2),
+            max_lifetime_secs: Some(1800),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut inst = TaskInstance {
+            id: 1,
+            task_name: "foo_task".into(),
+            container_id: String::new(),
+            created_at: now,
+            expires_at: now + chrono::Duration::seconds(900),
+            status: InstanceStatus::Running,
+            user_id: 1,
+            endpoint: String::new(),
+            team_id: None,
+            flag: None,
+            extensions: 0,
+            stop_reason: None,
+            node: "local".into(),
+            endpoints: Vec::new(),
+        };
+
+        let first = extension_expiry(&inst, &task, &cfg.ports).unwrap();
+        assert_eq!(first, inst.expires_at + chrono::Duration::seconds(600));
+
+        inst.expires_at = first;
+        inst.extensions = 1;
+        let capped = extension_expiry(&inst, &task, &cfg.ports).unwrap();
+        assert_eq!(capped, now + chrono::Duration::seconds(1800));
+
+        inst.extensions = 2;
+        assert_eq!(extensions_left(&inst, &task, &cfg.ports), 0);
+        assert!(matches!(extension_expiry(&inst, &task, &cfg.ports), Err(DeployError::Limit(_))));
+    }
+
     #[tokio::test]
     async fn deploy_and_stop() {
         let mut d = Deployer::new().await.unwrap();
-        let inst = d.deploy("foo_task").await.unwrap().instance;
+        let inst = d.deploy("foo_task", 0, None, config_manager::LOCAL_NODE).await.unwrap().instance;
 
         sleep(Duration::from_secs(20)).await;
 
-        d.stop(&inst).await.unwrap();
+        d.stop(&inst, StopReason::User).await.unwrap();
 
         let docker = Docker::connect_with_local_defaults().unwrap();
         let _ = docker
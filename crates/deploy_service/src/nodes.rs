@@ -0,0 +1,93 @@
+//! The pool of Docker daemons instances run on. Clients connect on first
+//! use from the current `[[nodes]]` config, so nodes added by a reload are
+//! picked up, and each node's health is re-checked by pinging it when the
+//! last check has gone stale.
+
+use crate::docker::DockerClient;
+use crate::error::DeployError;
+use config_manager::{Node, get_config};
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long a health check result stays fresh.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// A node that does not answer a ping within this is unhealthy.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) struct NodeHandle {
+    /// The config the client was built from; a change reconnects.
+    node: Node,
+    pub(crate) docker: DockerClient,
+    healthy: AtomicBool,
+    checked: Mutex<Option<Instant>>,
+}
+
+#[derive(Default)]
+pub(crate) struct NodePool {
+    handles: Mutex<HashMap<String, Arc<NodeHandle>>>,
+}
+
+impl NodeHandle {
+    async fn check(&self) {
+        let ok = matches!(tokio::time::timeout(PING_TIMEOUT, self.docker.ping()).await, Ok(Ok(())));
+        *self.checked.lock().unwrap() = Some(Instant::now());
+        match (self.healthy.swap(ok, Ordering::Relaxed), ok) {
+            (true, false) => warn!("Node {} is unreachable", self.node.name),
+            (false, true) => info!("Node {} is reachable again", self.node.name),
+            _ => {}
+        }
+    }
+}
+
+impl NodePool {
+    /// The client for `name`. A node dropped from the config keeps its old
+    /// client, so its remaining instances can still be stopped.
+    pub(crate) fn get(&self, name: &str) -> Result<Arc<NodeHandle>, DeployError> {
+        let cfg = get_config();
+        let mut handles = self.handles.lock().unwrap();
+        let current = handles.get(name).cloned();
+        match (cfg.node(name), current) {
+            (Some(node), Some(h)) if h.node == *node => Ok(h),
+            (Some(node), _) => {
+                let h = Arc::new(NodeHandle {
+                    node: node.clone(),
+                    docker: DockerClient::connect(node)?,
+                    healthy: AtomicBool::new(true),
+                    checked: Mutex::new(None),
+                });
+                handles.insert(name.to_string(), h.clone());
+                Ok(h)
+            }
+            (None, Some(h)) => Ok(h),
+            (None, None) => Err(DeployError::Config(format!("unknown node {}", name))),
+        }
+    }
+
+    /// Ping configured nodes whose last check is stale, all at once, so a
+    /// pass takes at most one ping timeout however many nodes are down.
+    pub(crate) async fn refresh(&self) {
+        let cfg = get_config();
+        let mut stale = Vec::new();
+        for node in cfg.nodes() {
+            match self.get(&node.name) {
+                Ok(h) if h.checked.lock().unwrap().is_some_and(|t| t.elapsed() < CHECK_INTERVAL) => {}
+                Ok(h) => stale.push(h),
+                Err(e) => warn!("Node {} unusable: {}", node.name, e),
+            }
+        }
+        join_all(stale.iter().map(|h| h.check())).await;
+    }
+
+    /// Last known health; nodes not checked yet count as healthy.
+    pub(crate) fn is_healthy(&self, name: &str) -> bool {
+        self.handles
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_none_or(|h| h.healthy.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,187 @@
+//! Readiness probes, run once an instance's containers have started and
+//! before the instance is handed out. `tcp` and `http` probes run inside the
+//! node's router, which is already on the instance network, with `nc` and
+//! `wget` as found in the official Traefik image; nothing else joins the
+//! network. `docker` probes follow the image's `HEALTHCHECK`.
+
+use crate::docker::DockerClient;
+use crate::error::DeployError;
+use bollard::models::{ContainerInspectResponse, HealthStatusEnum};
+use config_manager::{Probe, Readiness};
+use std::time::Duration;
+use tokio::time::{Instant, sleep, timeout};
+
+/// Pause between attempts.
+const INTERVAL: Duration = Duration::from_millis(500);
+/// Limit on a single connection attempt, in seconds as the tools take it.
+const ATTEMPT_SECS: u64 = 2;
+/// Lines of container output kept when a probe fails.
+const LOG_TAIL: u32 = 50;
+
+/// Outcome of one probe attempt.
+#[derive(Debug, PartialEq)]
+enum Attempt {
+    Ready,
+    /// Not yet; worth trying again.
+    Pending(String),
+    /// Will not get better, e.g. the container exited.
+    Failed(String),
+}
+
+/// Wait until `container_id`, listening on `port`, passes `readiness`,
+/// probing from `router`. On failure the error carries the tail of the
+/// container's output.
+pub(crate) async fn wait_ready(
+    docker: &DockerClient,
+    container_id: &str,
+    network: &str,
+    port: u16,
+    readiness: &Readiness,
+    router: &str,
+) -> Result<(), DeployError> {
+    if readiness.probe == Probe::None {
+        return Ok(());
+    }
+    if let Err(reason) = poll(docker, container_id, network, port, readiness, router).await {
+        let logs = docker.logs(container_id, LOG_TAIL).await.unwrap_or_default();
+        return Err(DeployError::NotReady { reason, logs });
+    }
+    Ok(())
+}
+
+async fn poll(
+    docker: &DockerClient,
+    container_id: &str,
+    network: &str,
+    port: u16,
+    readiness: &Readiness,
+    router: &str,
+) -> Result<(), String> {
+    let deadline = Instant::now() + Duration::from_secs(readiness.timeout_secs);
+    loop {
+        let state = docker.inspect_container(container_id).await.map_err(|e| e.to_string())?;
+        let pending = match attempt(docker, &state, network, port, readiness, router).await {
+            Attempt::Ready => return Ok(()),
+            Attempt::Failed(why) => return Err(why),
+            Attempt::Pending(why) => why,
+        };
+        if Instant::now() + INTERVAL >= deadline {
+            return Err(format!("{} after {}s", pending, readiness.timeout_secs));
+        }
+        sleep(INTERVAL).await;
+    }
+}
+
+async fn attempt(
+    docker: &DockerClient,
+    state: &ContainerInspectResponse,
+    network: &str,
+    port: u16,
+    readiness: &Readiness,
+    router: &str,
+) -> Attempt {
+    let s = state.state.as_ref();
+    if s.and_then(|s| s.running) == Some(false) {
+        let code = s.and_then(|s| s.exit_code).unwrap_or_default();
+        return Attempt::Failed(format!("container exited with code {}", code));
+    }
+    if readiness.probe == Probe::Docker {
+        return health(s.and_then(|s| s.health.as_ref()).and_then(|h| h.status));
+    }
+    let ip = state
+        .network_settings
+        .as_ref()
+        .and_then(|n| n.networks.as_ref())
+        .and_then(|n| n.get(network))
+        .and_then(|e| e.ip_address.as_deref())
+        .filter(|ip| !ip.is_empty());
+    let Some(ip) = ip else {
+        return Attempt::Pending(format!("no address on {}", network));
+    };
+    let cmd = command(ip, port, readiness);
+    match timeout(Duration::from_secs(ATTEMPT_SECS + 1), docker.exec(router, cmd)).await {
+        Ok(Ok((code, output))) => judge(code, &output, port, readiness),
+        Ok(Err(e)) => Attempt::Failed(format!("cannot probe from router {}: {}", router, e)),
+        Err(_) => Attempt::Pending(format!("probe of port {} timed out", port)),
+    }
+}
+
+fn health(status: Option<HealthStatusEnum>) -> Attempt {
+    match status {
+        Some(HealthStatusEnum::HEALTHY) => Attempt::Ready,
+        Some(HealthStatusEnum::STARTING) => Attempt::Pending("healthcheck still starting".into()),
+        Some(HealthStatusEnum::UNHEALTHY) => Attempt::Failed("healthcheck reports unhealthy".into()),
+        _ => Attempt::Failed("image has no HEALTHCHECK".into()),
+    }
+}
+
+/// The command run in the router for a `tcp` or `http` probe of `ip:port`.
+fn command(ip: &str, port: u16, readiness: &Readiness) -> Vec<String> {
+    let secs = ATTEMPT_SECS.to_string();
+    let port = port.to_string();
+    let url = format!("http://{}:{}{}", ip, port, readiness.path);
+    let args = if readiness.probe == Probe::Tcp {
+        vec!["nc", "-z", "-w", &secs, ip, &port]
+    } else {
+        vec!["wget", "-S", "-q", "-O", "/dev/null", "-T", &secs, &url]
+    };
+    args.into_iter().map(String::from).collect()
+}
+
+/// Read the result of [`command`]. `wget -S` prints the response headers,
+/// status line first, whatever the status.
+fn judge(code: i64, output: &str, port: u16, readiness: &Readiness) -> Attempt {
+    if readiness.probe == Probe::Tcp {
+        return match code {
+            0 => Attempt::Ready,
+            _ => Attempt::Pending(format!("connect to port {} failed", port)),
+        };
+    }
+    match http_status(output) {
+        Some(status) if status == readiness.status => Attempt::Ready,
+        Some(status) => Attempt::Pending(format!("GET {} answered {}", readiness.path, status)),
+        None => Attempt::Pending(format!("GET {}: {}", readiness.path, output.lines().last().unwrap_or("no answer").trim())),
+    }
+}
+
+/// Status code of the first `HTTP/x.y nnn` line in `output`.
+fn http_status(output: &str) -> Option<u16> {
+    output
+        .lines()
+        .map(str::trim_start)
+        .find(|l| l.starts_with("HTTP/"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_and_tcp_probes() {
+        let http = Readiness { probe: Probe::Http, ..Default::default() };
+        let ok = "  HTTP/1.1 200 OK\n  Content-Type: text/html\n";
+        assert_eq!(judge(0, ok, 3000, &http), Attempt::Ready);
+        // busybox wget exits 1 on error statuses but still prints them
+        let starting = "  HTTP/1.1 502 Bad Gateway\nwget: server returned error: HTTP/1.1 502 Bad Gateway\n";
+        assert_eq!(judge(1, starting, 3000, &http), Attempt::Pending("GET / answered 502".into()));
+        let refused = "wget: can't connect to remote host (10.210.0.2): Connection refused\n";
+        assert!(matches!(judge(1, refused, 3000, &http), Attempt::Pending(_)));
+        let not_found = Readiness { status: 404, ..http };
+        assert_eq!(judge(8, "HTTP/1.1 404 Not Found\n", 3000, &not_found), Attempt::Ready);
+
+        let tcp = Readiness { probe: Probe::Tcp, ..Default::default() };
+        assert_eq!(command("10.210.0.2", 9000, &tcp), ["nc", "-z", "-w", "2", "10.210.0.2", "9000"]);
+        assert_eq!(judge(0, "", 9000, &tcp), Attempt::Ready);
+        assert!(matches!(judge(1, "", 9000, &tcp), Attempt::Pending(_)));
+    }
+
+    #[test]
+    fn docker_health_states() {
+        assert_eq!(health(Some(HealthStatusEnum::HEALTHY)), Attempt::Ready);
+        assert!(matches!(health(Some(HealthStatusEnum::STARTING)), Attempt::Pending(_)));
+        assert!(matches!(health(Some(HealthStatusEnum::UNHEALTHY)), Attempt::Failed(_)));
+        assert!(matches!(health(None), Attempt::Failed(_)));
+    }
+}
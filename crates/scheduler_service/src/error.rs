@@ -10,4 +10,7 @@ pub enum SchedulerError {
     #[error("deploy error: {0}")]
     Deploy(#[from] DeployError),
 
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+
 }
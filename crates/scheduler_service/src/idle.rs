@@ -0,0 +1,169 @@
+//! Idle detection: sample each running instance's CPU and network counters
+//! every pass, and stop instances whose counters have not moved enough for
+//! longer than the task's idle timeout. Thresholds are rates, so they mean
+//! the same whatever the spacing between passes.
+//!
+//! Samples live in memory only. After a restart or a change of leader every
+//! instance starts with a fresh idle clock, which errs towards keeping it.
+
+use chrono::{DateTime, Duration, Utc};
+use common::{InstanceStatus, StopReason, TaskInstance};
+use config_manager::{Config, Idle};
+use data_models::{Db, InstanceFilter};
+use deploy_service::{Deployer, Usage};
+use std::collections::HashMap;
+use tracing::{error, info, warn};
+
+use crate::error::SchedulerError;
+use crate::leader::Leader;
+use crate::webhook;
+
+pub const IDLE_STOP: &str = "idle_stop";
+
+struct Sample {
+    usage: Usage,
+    at: DateTime<Utc>,
+    active_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct IdleTracker {
+    samples: HashMap<i32, Sample>,
+}
+
+/// Whether the counters moved enough over the `secs` between two samples
+/// to count as use, against the per-second thresholds in `cfg`. Counters
+/// going backwards mean the container restarted, which counts too.
+pub fn is_active(prev: Usage, cur: Usage, secs: f64, cfg: &Idle) -> bool {
+    if cur.cpu_nanos < prev.cpu_nanos || cur.net_bytes < prev.net_bytes {
+        return true;
+    }
+    // Back-to-back samples must not turn a few bytes into a high rate.
+    let secs = secs.max(1.0);
+    let net = (cur.net_bytes - prev.net_bytes) as f64 / secs;
+    let cpu_millis = (cur.cpu_nanos - prev.cpu_nanos) as f64 / 1e6 / secs;
+    net >= cfg.min_net_bytes.max(0) as f64 || cpu_millis >= cfg.min_cpu_millis as f64
+}
+
+impl IdleTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a sample for `id` and return when it was last active.
+    pub fn observe(&mut self, id: i32, usage: Usage, now: DateTime<Utc>, cfg: &Idle) -> DateTime<Utc> {
+        let sample = self.samples.entry(id).or_insert(Sample { usage, at: now, active_at: now });
+        let secs = (now - sample.at).num_milliseconds() as f64 / 1000.0;
+        if is_active(sample.usage, usage, secs, cfg) {
+            sample.active_at = now;
+        }
+        sample.usage = usage;
+        sample.at = now;
+        sample.active_at
+    }
+
+    /// Forget everything, e.g. after losing the leadership.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    fn retain(&mut self, running: &[TaskInstance]) {
+        self.samples.retain(|id, _| running.iter().any(|i| i.id == *id));
+    }
+}
+
+/// Sample running instances and stop those idle past their timeout. Gives
+/// up part way through once this replica no longer leads.
+pub async fn stop_idle(
+    deploy: &mut Deployer,
+    db: &Db,
+    tracker: &mut IdleTracker,
+    leader: &mut Leader,
+    cfg: &Config,
+) -> Result<(), SchedulerError> {
+    let running = db.list_instances_filtered(&InstanceFilter {
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    })?;
+    tracker.retain(&running);
+
+    for inst in running {
+        let timeout = cfg.task(&inst.task_name).idle_timeout_secs(&cfg.idle);
+        if timeout == 0 {
+            continue;
+        }
+        if !leader.still_leading(db, cfg.scheduler.lease_ttl_secs) {
+            break;
+        }
+        let usage = match deploy.usage(&inst).await {
+            Ok(u) => u,
+            Err(e) => {
+                // Reconciliation deals with containers that are gone.
+                warn!("No stats for instance {}: {}", inst.id, e);
+                continue;
+            }
+        };
+        let now = Utc::now();
+        let active_at = tracker.observe(inst.id, usage, now, &cfg.idle);
+        if now - active_at < Duration::seconds(timeout as i64) {
+            continue;
+        }
+
+        info!("Instance {} idle since {} → stopping container {}", inst.id, active_at, inst.container_id);
+        if let Err(e) = deploy.stop(&inst, StopReason::Idle).await {
+            error!("Failed to stop idle {}: {}", inst.id, e);
+            continue;
+        }
+        let message = format!(
+            "Your {} instance (#{}) was stopped after {} minutes without activity.",
+            inst.task_name,
+            inst.id,
+            timeout / 60
+        );
+        // Around a change of leader two replicas may both stop it; announce it once.
+        let dedupe_key = format!("{}:{}", IDLE_STOP, inst.id);
+        match db.record_instance_event(&inst, IDLE_STOP, &message, Some(&dedupe_key)) {
+            Ok(Some(event)) => webhook::dispatch(&cfg.notifications.webhooks, &event),
+            Ok(None) => {}
+            Err(e) => error!("Failed to record idle stop of {}: {}", inst.id, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle_cfg() -> Idle {
+        Idle { timeout_secs: 600, min_net_bytes: 200, min_cpu_millis: 5 }
+    }
+
+    fn usage(cpu_millis: u64, net_bytes: u64) -> Usage {
+        Usage { cpu_nanos: cpu_millis * 1_000_000, net_bytes }
+    }
+
+    #[test]
+    fn activity_thresholds() {
+        let cfg = idle_cfg();
+        assert!(!is_active(usage(100, 1000), usage(120, 2000), 10.0, &cfg));
+        assert!(is_active(usage(100, 1000), usage(150, 1000), 10.0, &cfg));
+        assert!(is_active(usage(100, 1000), usage(100, 3000), 10.0, &cfg));
+        assert!(is_active(usage(100, 1000), usage(5, 10), 10.0, &cfg));
+        // The same movement spread over twice the time is half the rate.
+        assert!(!is_active(usage(100, 1000), usage(150, 1000), 20.0, &cfg));
+        assert!(!is_active(usage(100, 1000), usage(100, 3000), 20.0, &cfg));
+    }
+
+    #[test]
+    fn idle_clock_resets_on_activity() {
+        let cfg = idle_cfg();
+        let mut t = IdleTracker::new();
+        let start = Utc::now();
+        let later = start + Duration::seconds(300);
+        assert_eq!(t.observe(1, usage(100, 0), start, &cfg), start);
+        assert_eq!(t.observe(1, usage(101, 10), later, &cfg), start);
+        let busy = later + Duration::seconds(10);
+        assert_eq!(t.observe(1, usage(101, 10_000), busy, &cfg), busy);
+    }
+}
@@ -0,0 +1,84 @@
+//! Leader election over a lease row in `scheduler_leases`, so several
+//! scheduler replicas can run and only one acts at a time. A lease row is
+//! used rather than a session advisory lock because pgbouncer runs in
+//! transaction mode, where session locks do not stick to a client.
+
+use data_models::Db;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const LEASE_NAME: &str = "scheduler";
+
+pub struct Leader {
+    holder: String,
+    leading: bool,
+    renewed_at: Option<Instant>,
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Leader {
+    pub fn new() -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "scheduler".into());
+        let started = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        Self {
+            holder: format!("{}-{}-{}", host, std::process::id(), started),
+            leading: false,
+            renewed_at: None,
+        }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Take or renew the lease. Database errors count as not leading, so a
+    /// replica that cannot reach Postgres stops acting.
+    pub fn refresh(&mut self, db: &Db, ttl_secs: u64) -> bool {
+        let leading = match db.try_acquire_lease(LEASE_NAME, &self.holder, ttl_secs) {
+            Ok(held) => held,
+            Err(e) => {
+                error!("Lease refresh failed: {}", e);
+                false
+            }
+        };
+        match (self.leading, leading) {
+            (false, true) => info!("Scheduler {} is now the leader", self.holder),
+            (true, false) => warn!("Scheduler {} lost the leadership", self.holder),
+            _ => {}
+        }
+        self.leading = leading;
+        self.renewed_at = leading.then(Instant::now);
+        leading
+    }
+
+    /// For long passes: whether this replica still leads, renewing the
+    /// lease once a third of its TTL has gone by since the last renewal.
+    /// Checked before each instance is acted on, so a pass that outlasts the
+    /// lease stops instead of racing the next leader.
+    pub fn still_leading(&mut self, db: &Db, ttl_secs: u64) -> bool {
+        if !self.leading {
+            return false;
+        }
+        match self.renewed_at {
+            Some(t) if t.elapsed() < Duration::from_secs(ttl_secs) / 3 => true,
+            _ => self.refresh(db, ttl_secs),
+        }
+    }
+
+    /// Hand the lease over on shutdown instead of letting it time out.
+    pub fn release(&mut self, db: &Db) {
+        if self.leading {
+            match db.release_lease(LEASE_NAME, &self.holder) {
+                Ok(_) => info!("Scheduler {} released the leadership", self.holder),
+                Err(e) => error!("Lease release failed: {}", e),
+            }
+            self.leading = false;
+            self.renewed_at = None;
+        }
+    }
+}
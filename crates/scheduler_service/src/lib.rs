@@ -1,37 +1,104 @@
-mod error;
+pub mod error;
+pub mod idle;
+pub mod leader;
+pub mod notify;
+pub mod reconcile;
+pub mod webhook;
 
 use chrono::Utc;
-use tokio::time::{sleep, Duration};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{sleep, Duration, Instant};
 use tracing::{info, error};
 
 use config_manager::get_config;
-use common::InstanceStatus;
+use common::StopReason;
 use data_models::Db;
 use deploy_service::Deployer;
 use crate::error::SchedulerError;
+use crate::idle::{IdleTracker, stop_idle};
+use crate::leader::Leader;
+use crate::notify::warn_expiring;
+use crate::reconcile::reconcile;
 
+/// Run the scheduler until SIGTERM or Ctrl-C. Every replica runs this
+/// loop, but only the holder of the lease warns about, expires, idles out
+/// and reconciles instances.
 pub async fn run() -> Result<(), SchedulerError> {
     // 1. Bring up your deployer & DB once
     let mut deploy = Deployer::new().await?;
     let db         = Db::new()?;
-    let interval   = get_config().scheduler.poll_interval_secs;
+    let mut leader = Leader::new();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut last_reconcile: Option<Instant> = None;
+    let mut idle = IdleTracker::new();
 
     loop {
-        let now     = Utc::now();
-        let expired = db.list_expired_instances(now)?;
+        let cfg = get_config();
+        let lease_ttl = cfg.scheduler.lease_ttl_secs;
 
-        for inst in expired {
-            info!("Instance {} expired → stopping container {}", inst.id, inst.container_id);
-
-            if let Err(e) = deploy.stop(&inst).await {
-                error!("Failed to stop {}: {}", inst.id, e);
+        if leader.refresh(&db, lease_ttl) {
+            let due = last_reconcile.is_none_or(|t| {
+                t.elapsed() >= Duration::from_secs(cfg.scheduler.reconcile_interval_secs)
+            });
+            if due {
+                // Also runs first thing after taking over, since the previous
+                // leader may have died mid-deploy.
+                if let Err(e) = reconcile(&deploy, &db, cfg.scheduler.orphan_grace_secs).await {
+                    error!("Reconciliation failed: {}", e);
+                }
+                last_reconcile = Some(Instant::now());
+            }
+            if let Err(e) = warn_expiring(&db, &cfg.notifications, Utc::now()) {
+                error!("Expiry warnings failed: {}", e);
+            }
+            if let Err(e) = stop_expired(&mut deploy, &db, &mut leader, lease_ttl).await {
+                error!("Expiry check failed: {}", e);
+            }
+            if let Err(e) = stop_idle(&mut deploy, &db, &mut idle, &mut leader, &cfg).await {
+                error!("Idle check failed: {}", e);
             }
+        } else {
+            last_reconcile = None;
+            idle.clear();
+        }
+
+        // Wake often enough to renew the lease before it lapses, and for
+        // followers to notice quickly when it does. Re-read each round so a
+        // config reload can change the intervals.
+        let pause = cfg.scheduler.poll_interval_secs.min(lease_ttl / 3).max(1);
+        tokio::select! {
+            _ = sleep(Duration::from_secs(pause)) => {}
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    info!("Scheduler {} shutting down", leader.holder());
+    leader.release(&db);
+    Ok(())
+}
+
+async fn stop_expired(
+    deploy: &mut Deployer,
+    db: &Db,
+    leader: &mut Leader,
+    lease_ttl: u64,
+) -> Result<(), SchedulerError> {
+    let now     = Utc::now();
+    let expired = db.list_expired_instances(now)?;
 
-            if let Err(e) = db.update_instance_status(inst.id, InstanceStatus::Stopped) {
+    for inst in expired {
+        if !leader.still_leading(db, lease_ttl) {
+            break;
+        }
+        info!("Instance {} expired → stopping container {}", inst.id, inst.container_id);
+
+        if let Err(e) = deploy.stop(&inst, StopReason::Expired).await {
+            error!("Failed to stop {}: {}", inst.id, e);
+            if let Err(e) = db.stop_instance(inst.id, StopReason::Expired) {
                 error!("Failed to mark {} stopped in DB: {}", inst.id, e);
             }
         }
-
-        sleep(Duration::from_secs(interval)).await;
     }
+    Ok(())
 }
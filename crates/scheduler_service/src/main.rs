@@ -1,14 +1,16 @@
-mod error;
-
-use common::init_logging;
+use common::{init_logging, reload::watch_config};
 use tracing::{info, error};
 use scheduler_service::run;
-use crate::error::SchedulerError;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     init_logging();
+    if let Err(e) = config_manager::init(config_manager::config_arg().as_deref()) {
+        error!("config error: {}", e);
+        std::process::exit(1);
+    }
     info!("Scheduler starting up");
+    tokio::spawn(watch_config(|_, _| async {}));
 
     if let Err(e) = run().await {
         error!("Scheduler failed: {}", e);
@@ -0,0 +1,88 @@
+//! Expiry warnings: as a running instance crosses each configured threshold
+//! before `expires_at`, record an `expiry_warning` event for the player
+//! event stream and hand it to the webhooks.
+
+use chrono::{DateTime, Utc};
+use common::{InstanceStatus, TaskInstance};
+use config_manager::Notifications;
+use data_models::{Db, InstanceFilter};
+use tracing::{error, info};
+
+use crate::error::SchedulerError;
+use crate::webhook;
+
+pub const EXPIRY_WARNING: &str = "expiry_warning";
+
+/// The threshold a warning is due for: the smallest one `remaining` has
+/// dropped below. Only the latest is sent when several are crossed between
+/// polls.
+pub fn due_threshold(remaining_secs: i64, thresholds: &[u64]) -> Option<u64> {
+    if remaining_secs <= 0 {
+        return None;
+    }
+    thresholds
+        .iter()
+        .copied()
+        .filter(|t| remaining_secs <= *t as i64)
+        .min()
+}
+
+/// The key that keeps a warning from repeating. It includes the expiry, so
+/// an extended instance is warned again.
+pub fn dedupe_key(inst: &TaskInstance, threshold: u64) -> String {
+    format!("{}:{}:{}:{}", EXPIRY_WARNING, inst.id, threshold, inst.expires_at.timestamp())
+}
+
+pub fn warning_message(inst: &TaskInstance, remaining_secs: i64) -> String {
+    let left = if remaining_secs >= 120 {
+        format!("{} minutes", remaining_secs / 60)
+    } else {
+        format!("{} seconds", remaining_secs)
+    };
+    format!(
+        "Your {} instance (#{}) expires in {}; extend it to keep it running.",
+        inst.task_name, inst.id, left
+    )
+}
+
+/// Record and dispatch the warnings due at `now`.
+pub fn warn_expiring(db: &Db, cfg: &Notifications, now: DateTime<Utc>) -> Result<(), SchedulerError> {
+    if cfg.expiry_warning_secs.is_empty() {
+        return Ok(());
+    }
+    let running = db.list_instances_filtered(&InstanceFilter {
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    })?;
+    for inst in running {
+        let remaining = (inst.expires_at - now).num_seconds();
+        let Some(threshold) = due_threshold(remaining, &cfg.expiry_warning_secs) else {
+            continue;
+        };
+        let message = warning_message(&inst, remaining);
+        match db.record_instance_event(&inst, EXPIRY_WARNING, &message, Some(&dedupe_key(&inst, threshold))) {
+            Ok(Some(event)) => {
+                info!("Instance {} expires in {}s → warning sent", inst.id, remaining);
+                webhook::dispatch(&cfg.webhooks, &event);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to record expiry warning for {}: {}", inst.id, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_smallest_crossed_threshold() {
+        let t = [300, 60];
+        assert_eq!(due_threshold(600, &t), None);
+        assert_eq!(due_threshold(300, &t), Some(300));
+        assert_eq!(due_threshold(90, &t), Some(300));
+        assert_eq!(due_threshold(45, &t), Some(60));
+        assert_eq!(due_threshold(0, &t), None);
+    }
+}
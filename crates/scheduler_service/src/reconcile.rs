@@ -0,0 +1,186 @@
+//! Bring Docker and the `instances` table back in line after a crash:
+//! containers and networks no running instance refers to, directly or as a
+//! member of its container group, are removed, and running
+//! instances with no container left in their group are marked failed.
+//! Instances on nodes that could not be listed are left alone.
+
+use chrono::{DateTime, Duration, Utc};
+use common::{InstanceStatus, TaskInstance};
+use data_models::{Db, InstanceFilter};
+use deploy_service::{Deployer, Inventory, instance_group};
+use std::collections::HashSet;
+use tracing::{error, info, warn};
+
+use crate::error::SchedulerError;
+
+/// What a reconciliation pass will do.
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    /// Managed containers without a running instance, past the grace
+    /// period, as (node, container id).
+    pub orphans: Vec<(String, String)>,
+    /// Instance networks without a running instance, past the grace
+    /// period, as (node, network name).
+    pub networks: Vec<(String, String)>,
+    /// Running instances with no container left, as (instance id, the
+    /// container id the row held).
+    pub missing: Vec<(i32, String)>,
+}
+
+pub fn plan(
+    inventory: &Inventory,
+    running: &[TaskInstance],
+    now: DateTime<Utc>,
+    grace: Duration,
+) -> Plan {
+    let containers = &inventory.containers;
+    let referenced: HashSet<&str> = running.iter().map(|i| i.container_id.as_str()).collect();
+    let groups: HashSet<&str> = running.iter().filter_map(instance_group).collect();
+    let existing: HashSet<&str> = containers.iter().map(|c| c.id.as_str()).collect();
+    // A reset creates the new members before removing the old ones, so a
+    // group with any container left is still alive.
+    let live_groups: HashSet<&str> = containers.iter().filter_map(|c| c.group.as_deref()).collect();
+
+    Plan {
+        orphans: containers
+            .iter()
+            .filter(|c| c.managed && !referenced.contains(c.id.as_str()))
+            .filter(|c| !c.group.as_deref().is_some_and(|g| groups.contains(g)))
+            .filter(|c| now - c.created_at >= grace)
+            .map(|c| (c.node.clone(), c.id.clone()))
+            .collect(),
+        networks: inventory
+            .networks
+            .iter()
+            .filter(|n| !n.group.as_deref().is_some_and(|g| groups.contains(g)))
+            .filter(|n| now - n.created_at >= grace)
+            .map(|n| (n.node.clone(), n.name.clone()))
+            .collect(),
+        missing: running
+            .iter()
+            .filter(|i| inventory.reachable.contains(&i.node))
+            .filter(|i| !existing.contains(i.container_id.as_str()))
+            .filter(|i| !instance_group(i).is_some_and(|g| live_groups.contains(g)))
+            .map(|i| (i.id, i.container_id.clone()))
+            .collect(),
+    }
+}
+
+/// Run one reconciliation pass.
+pub async fn reconcile(deploy: &Deployer, db: &Db, grace_secs: u64) -> Result<Plan, SchedulerError> {
+    // Rows first: a deploy finishing in between then shows up as a fresh
+    // container, which the grace period spares, never as a missing one.
+    let running = db.list_instances_filtered(&InstanceFilter {
+        status: Some(InstanceStatus::Running),
+        ..Default::default()
+    })?;
+    let inventory = deploy.inventory().await?;
+    let plan = plan(&inventory, &running, Utc::now(), Duration::seconds(grace_secs as i64));
+
+    for (node, id) in &plan.orphans {
+        let Some(c) = inventory.containers.iter().find(|c| &c.id == id) else { continue };
+        warn!(
+            "Removing orphan container {} on node {} (task {:?}, user {:?})",
+            id, node, c.task_name, c.user_id
+        );
+        if let Err(e) = deploy.remove_orphan(c).await {
+            error!("Failed to remove orphan {}: {}", id, e);
+        }
+    }
+    // After the orphans, so their networks are empty.
+    for (node, name) in &plan.networks {
+        let Some(n) = inventory.networks.iter().find(|n| &n.node == node && &n.name == name) else { continue };
+        info!("Removing network {} on node {} left by a stopped instance", name, node);
+        if let Err(e) = deploy.remove_network(n).await {
+            warn!("Failed to remove network {}: {}", name, e);
+        }
+    }
+    for (id, container_id) in &plan.missing {
+        match db.fail_lost_instance(*id, container_id) {
+            Ok(true) => warn!("Instance {} lost its container → marked failed", id),
+            Ok(false) => info!("Instance {} changed during reconciliation, left alone", id),
+            Err(e) => error!("Failed to mark {} failed in DB: {}", id, e),
+        }
+    }
+    info!(
+        "Reconciled: {} orphan(s) and {} network(s) removed, {} instance(s) failed",
+        plan.orphans.len(),
+        plan.networks.len(),
+        plan.missing.len()
+    );
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deploy_service::{ContainerInfo, NetworkInfo};
+
+    fn container(id: &str, managed: bool, age_secs: i64, now: DateTime<Utc>) -> ContainerInfo {
+        ContainerInfo {
+            id: id.into(),
+            node: "local".into(),
+            created_at: now - Duration::seconds(age_secs),
+            managed,
+            task_name: Some("foo_task".into()),
+            user_id: Some(1),
+            group: None,
+        }
+    }
+
+    fn instance(id: i32, container_id: &str, node: &str, now: DateTime<Utc>) -> TaskInstance {
+        TaskInstance {
+            id,
+            task_name: "foo_task".into(),
+            container_id: container_id.into(),
+            created_at: now,
+            expires_at: now,
+            status: InstanceStatus::Running,
+            user_id: 1,
+            endpoint: String::new(),
+            team_id: None,
+            flag: None,
+            extensions: 0,
+            stop_reason: None,
+            node: node.into(),
+            endpoints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn plan_finds_orphans_and_missing() {
+        let now = Utc::now();
+        let inventory = Inventory {
+            containers: vec![
+                container("live", true, 600, now),
+                container("orphan", true, 600, now),
+                container("fresh", true, 5, now),
+                container("foreign", false, 600, now),
+                ContainerInfo { group: Some("abc".into()), ..container("db", true, 600, now) },
+                ContainerInfo { group: Some("old".into()), ..container("stale-db", true, 600, now) },
+            ],
+            networks: ["abc", "old", "new"]
+                .map(|g| NetworkInfo {
+                    name: format!("ctf-{}", g),
+                    node: "local".into(),
+                    created_at: now - Duration::seconds(if g == "new" { 5 } else { 600 }),
+                    group: Some(g.into()),
+                })
+                .into(),
+            reachable: vec!["local".into()],
+        };
+        let running = [
+            TaskInstance { endpoint: "http://abc.ctf.local".into(), ..instance(1, "live", "local", now) },
+            instance(2, "gone", "local", now),
+            instance(3, "elsewhere", "down", now),
+            // Reset in progress: old container gone, new group member up.
+            TaskInstance { endpoint: "http://abc.ctf.local".into(), ..instance(4, "replaced", "local", now) },
+        ];
+
+        let p = plan(&inventory, &running, now, Duration::seconds(120));
+        let orphans = ["orphan", "stale-db"].map(|id| ("local".to_string(), id.to_string()));
+        assert_eq!(p.orphans, orphans);
+        assert_eq!(p.networks, vec![("local".to_string(), "ctf-old".to_string())]);
+        assert_eq!(p.missing, vec![(2, "gone".to_string())]);
+    }
+}
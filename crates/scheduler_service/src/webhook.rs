@@ -0,0 +1,187 @@
+//! Outbound webhooks for instance events. Each delivery runs in its own
+//! task and is retried with doubling backoff, so a slow or failing endpoint
+//! never holds up the scheduler loop.
+
+use common::InstanceEvent;
+use config_manager::{Webhook, WebhookFormat};
+use serde_json::{Value, json};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Delay before the first retry; doubled for each one after.
+const RETRY_BASE: Duration = Duration::from_millis(500);
+
+/// Request body for `event` in the shape `format` expects.
+pub fn payload(format: WebhookFormat, event: &InstanceEvent) -> Value {
+    match format {
+        WebhookFormat::Json => json!(event),
+        WebhookFormat::Discord => json!({ "content": event.message }),
+        WebhookFormat::Slack => json!({ "text": event.message }),
+    }
+}
+
+/// Deliver `event` to every hook in the background.
+pub fn dispatch(hooks: &[Webhook], event: &InstanceEvent) {
+    for hook in hooks {
+        let hook = hook.clone();
+        let body = payload(hook.format, event);
+        let id = event.id;
+        tokio::spawn(async move {
+            if let Err(e) = deliver(&hook, &body, RETRY_BASE).await {
+                warn!("Webhook delivery of event {} failed: {}", id, e);
+            }
+        });
+    }
+}
+
+/// POST `body` to `hook`, retrying up to `hook.retries` times on network
+/// errors and non-2xx responses.
+pub async fn deliver(hook: &Webhook, body: &Value, backoff: Duration) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(hook.timeout_secs))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        let err = match client.post(hook.url.expose()).json(body).send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => format!("HTTP {}", resp.status()),
+            // Strip the URL: Discord and Slack URLs carry the token.
+            Err(e) => e.without_url().to_string(),
+        };
+        if attempt >= hook.retries {
+            return Err(format!("{} after {} attempt(s)", err, attempt + 1));
+        }
+        attempt += 1;
+        debug!("Webhook attempt {} failed ({}), retrying in {:?}", attempt, err, delay);
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// A minimal HTTP server on localhost that records request bodies, for
+/// testing webhook delivery without an outside service. Built for this
+/// crate's tests, and for other crates' with the `test-stub` feature.
+#[cfg(any(test, feature = "test-stub"))]
+pub mod stub {
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    pub struct StubServer {
+        pub url: String,
+        bodies: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StubServer {
+        /// Start a server answering 500 to the first `fail_first` requests
+        /// and 204 after that.
+        pub async fn start(fail_first: usize) -> std::io::Result<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let url = format!("http://{}/hook", listener.local_addr()?);
+            let bodies = Arc::new(Mutex::new(Vec::new()));
+            let seen = bodies.clone();
+            tokio::spawn(async move {
+                let mut count = 0;
+                while let Ok((mut sock, _)) = listener.accept().await {
+                    let body = read_body(&mut sock).await.unwrap_or_default();
+                    count += 1;
+                    let status = if count <= fail_first {
+                        "500 Internal Server Error"
+                    } else {
+                        seen.lock().unwrap().push(body);
+                        "204 No Content"
+                    };
+                    let reply = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                    let _ = sock.write_all(reply.as_bytes()).await;
+                }
+            });
+            Ok(Self { url, bodies })
+        }
+
+        /// Bodies of the requests answered with success, in arrival order.
+        pub fn bodies(&self) -> Vec<String> {
+            self.bodies.lock().unwrap().clone()
+        }
+    }
+
+    async fn read_body(sock: &mut tokio::net::TcpStream) -> std::io::Result<String> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = sock.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let len = text[..end]
+                    .lines()
+                    .find_map(|l| {
+                        let (k, v) = l.split_once(':')?;
+                        k.eq_ignore_ascii_case("content-length").then(|| v.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if buf.len() >= end + 4 + len {
+                    return Ok(String::from_utf8_lossy(&buf[end + 4..end + 4 + len]).into_owned());
+                }
+            }
+        }
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stub::StubServer;
+    use super::*;
+    use chrono::Utc;
+
+    fn event() -> InstanceEvent {
+        InstanceEvent {
+            id: 7,
+            instance_id: 3,
+            task_name: "foo_task".into(),
+            user_id: 1,
+            team_id: None,
+            kind: "expiry_warning".into(),
+            message: "foo_task expires in 5 minutes".into(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn hook(url: &str, format: WebhookFormat, retries: u32) -> Webhook {
+        Webhook { url: url.into(), format, retries, timeout_secs: 5 }
+    }
+
+    #[test]
+    fn payload_formats() {
+        let e = event();
+        assert_eq!(payload(WebhookFormat::Discord, &e), json!({ "content": e.message }));
+        assert_eq!(payload(WebhookFormat::Slack, &e), json!({ "text": e.message }));
+        let generic = payload(WebhookFormat::Json, &e);
+        assert_eq!(generic["kind"], "expiry_warning");
+        assert_eq!(generic["instance_id"], 3);
+    }
+
+    #[tokio::test]
+    async fn retries_until_delivered() {
+        let server = StubServer::start(2).await.unwrap();
+        let h = hook(&server.url, WebhookFormat::Slack, 2);
+        deliver(&h, &payload(h.format, &event()), Duration::from_millis(1)).await.unwrap();
+        let bodies = server.bodies();
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(serde_json::from_str::<Value>(&bodies[0]).unwrap()["text"], event().message);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let server = StubServer::start(5).await.unwrap();
+        let h = hook(&server.url, WebhookFormat::Json, 1);
+        let err = deliver(&h, &json!({}), Duration::from_millis(1)).await.unwrap_err();
+        assert!(err.contains("2 attempt(s)"), "{}", err);
+        assert!(server.bodies().is_empty());
+    }
+}
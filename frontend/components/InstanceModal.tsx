@@ -5,7 +5,7 @@ import {useEffect} from "react";
 import {Dialog, DialogTrigger, DialogContent, DialogHeader, DialogFooter, DialogTitle} from "@/components/ui/dialog";
 import {Button} from "@/components/ui/button";
 import {API_URL, authFetch} from "@/lib/api";
-import {Instance} from "@/types";
+import {Instance, QueueStatus} from "@/types";
 import {ClockIcon} from "@heroicons/react/24/outline";
 
 export interface InstanceModalProps {
@@ -26,10 +26,22 @@ const InstanceModal: FC<InstanceModalProps> = ({
     const [inst, setInst] = useState<Instance | null>(null);
     const [endpoint, setEndpoint] = useState<string>("");
 
+    const [queued, setQueued] = useState<QueueStatus | null>(null);
+    const [error, setError] = useState<string>("");
+
     const [secondsLeft, setSecondsLeft] = useState<number>(0);
 
+    function show(instance: Instance) {
+        setQueued(null);
+        setSecondsLeft(instance.expires_in_secs);
+        setInst(instance);
+        setEndpoint(instance.endpoint);
+        onDeploy(instance, instance.endpoint);
+    }
+
     async function start() {
         setBusy(true);
+        setError("");
         const res = await authFetch(`${API_URL}/deploy`, token, {
             method: "POST",
             body: JSON.stringify({
@@ -37,11 +49,48 @@ const InstanceModal: FC<InstanceModalProps> = ({
                 task: taskName,
             }),
         });
-        const data: { instance: Instance } = await res.json();
-        setSecondsLeft(data.instance.expires_in_secs);
-        setInst(data.instance);
-        setEndpoint(data.instance.endpoint);
-        onDeploy(data.instance, data.instance.endpoint);
+        if (!res.ok) {
+            setError(await res.json().catch(() => res.statusText));
+        } else if (res.status === 202) {
+            // No capacity right now; the deploy waits in the queue.
+            const data: { queued: QueueStatus } = await res.json();
+            setQueued(data.queued);
+        } else {
+            const data: { instance: Instance } = await res.json();
+            show(data.instance);
+        }
+        setBusy(false);
+    }
+
+    // while queued, poll our place until the deploy starts or fails
+    useEffect(() => {
+        if (!queued || queued.failure) return;
+        const id = setInterval(async () => {
+            const status: QueueStatus | null = await authFetch(`${API_URL}/queue`, token)
+                .then((r) => r.json());
+            if (status) {
+                setQueued(status);
+                return;
+            }
+            // Left the queue: the newest running instance of this task is ours.
+            const list: Instance[] = await authFetch(`${API_URL}/instances`, token)
+                .then((r) => r.json());
+            const started = list
+                .filter((i) => i.task_name === taskName && i.status === "Running")
+                .sort((a, b) => b.id - a.id)[0];
+            if (started) {
+                show(started);
+            } else {
+                setQueued(null);
+            }
+        }, 3000);
+        return () => clearInterval(id);
+    }, [queued, token, taskName]);
+
+    async function leaveQueue() {
+        setBusy(true);
+        await authFetch(`${API_URL}/queue/leave`, token, {method: "POST"});
+        setQueued(null);
         setBusy(false);
     }
 
@@ -72,7 +121,28 @@ const InstanceModal: FC<InstanceModalProps> = ({
                     <h3 className="text-lg font-medium">{taskName}</h3>
                 </DialogHeader>
 
-                {!inst ? (
+                {error && <p className="text-red-600">{error}</p>}
+
+                {queued ? (
+                    <div className="space-y-4">
+                        {queued.failure ? (
+                            <p className="text-red-600">Deploy failed: {queued.failure}</p>
+                        ) : (
+                            <p>Waiting for capacity, position {queued.position} in the queue…</p>
+                        )}
+                        <div className="flex gap-2">
+                            {queued.failure ? (
+                                <Button onClick={start} disabled={busy}>
+                                    Try Again
+                                </Button>
+                            ) : (
+                                <Button variant="secondary" onClick={leaveQueue} disabled={busy}>
+                                    Leave Queue
+                                </Button>
+                            )}
+                        </div>
+                    </div>
+                ) : !inst ? (
                     <div className="space-y-4">
                         <Button onClick={start} disabled={busy}>
                             {busy ? "Starting…" : "Start Instance"}
@@ -12,3 +12,14 @@ export interface Instance {
     endpoint: string;
     status: "Running" | "Stopped" | "Expired";
 }
+
+/** A deploy waiting for capacity, as returned by `POST /deploy` (202) and `GET /queue`. */
+export interface QueueStatus {
+    id: number;
+    task_name: string;
+    /** 1 when next in line, 0 once the deploy failed. */
+    position: number;
+    queued_at: string;
+    failure: string | null;
+    failed_at: string | null;
+}